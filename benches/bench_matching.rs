@@ -7,7 +7,7 @@ use serde_json;
 
 use adblock;
 use adblock::utils::rules_from_lists;
-use adblock::blocker::{Blocker, BlockerOptions};
+use adblock::blocker::{Blocker, BlockerOptions, HotFilterOptions};
 use adblock::request::Request;
 use adblock::url_parser::UrlParser;
 use adblock::engine::Engine;
@@ -183,6 +183,40 @@ fn rule_match_parsed_elep_slimlist(c: &mut Criterion) {
     );
 }
 
+fn rule_match_hot_filters(c: &mut Criterion) {
+  let rules = rules_from_lists(&[
+    String::from("data/test/easylist.txt"),
+    String::from("data/test/easyprivacy.txt")
+  ]);
+  // Every page of the site list, loaded as a frame of the previous one
+  let sites = adblock::utils::read_file_lines("data/test/sitelist.txt");
+  let requests_parsed: Vec<_> = sites.windows(2)
+    .map(|pair| Request::from_urls(&pair[1], &pair[0], "sub_frame"))
+    .filter_map(Result::ok)
+    .collect();
+  let requests_parsed_copy = requests_parsed.clone();
+  let requests_len = requests_parsed.len() as u32;
+
+  let mut hot_blocker = get_blocker(&rules);
+  hot_blocker.with_hot_filters(HotFilterOptions { capacity: 32, ..HotFilterOptions::default() });
+  let cold_blocker = get_blocker(&rules);
+
+  c.bench(
+        "rule-match-hot-filters",
+        Benchmark::new(
+            "hot",
+            move |b| {
+              b.iter(|| bench_matching_only(&hot_blocker, &requests_parsed))
+            },
+        )
+        .with_function("disabled", move |b| {
+              b.iter(|| bench_matching_only(&cold_blocker, &requests_parsed_copy))
+            },)
+        .throughput(Throughput::Elements(requests_len))
+        .sample_size(10)
+    );
+}
+
 fn serialization(c: &mut Criterion) {
   c.bench(
         "blocker-serialization",
@@ -364,6 +398,7 @@ criterion_group!(
   rule_match_parsed_el,
   rule_match_parsed_elep_slimlist,
   rule_match_browserlike_comparable,
+  rule_match_hot_filters,
   serialization,
   deserialization
 );
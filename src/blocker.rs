@@ -1,17 +1,26 @@
 use hashbrown::HashMap;
 use std::sync::Arc;
+use std::cell::RefCell;
+use lru::LruCache;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::iter::FromIterator;
+use std::mem::size_of;
 
 #[cfg(feature = "object-pooling")]
 use lifeguard::Pool;
 
-use crate::filters::network::{NetworkFilter, NetworkMatchable, FilterError};
-use crate::request::Request;
+use crate::filters::network::{NetworkFilter, NetworkFilterMask, NetworkMatchable, FilterError, FilterPart, CompiledRegex, RegexLimits};
+use crate::request::{Request, RequestType};
 use crate::utils::{fast_hash, Hash};
 use crate::optimizer;
 use crate::resources::{Resources, Resource};
+use crate::token_profile::TokenProfile;
+use crate::compact::{Interner, ListMemory, MemoryCounter, MemoryReport};
+use crate::observer::{BlockerEvent, BlockerObserver};
+use crate::serialization::{BucketIndex, IndexedMetadata, LazyBuckets};
+#[cfg(feature = "multi-pattern-matching")]
+use crate::filters::multi_pattern::PlainPatterns;
 use base64;
 use crate::utils;
 
@@ -22,13 +31,78 @@ pub struct BlockerOptions {
     pub load_network_filters: bool,
 }
 
-#[derive(Debug, Serialize)]
+/**
+ * Tuning of the hot filter cache: filters of the main list that matched at
+ * least `promote_after` requests are promoted into a list of at most
+ * `capacity` filters, which is checked before the full list. A `capacity`
+ * of 0 disables the cache, which is the default: buckets are usually small
+ * enough already, and when several filters match a request the one reported
+ * can depend on the requests seen before.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HotFilterOptions {
+    pub capacity: usize,
+    pub promote_after: u32,
+}
+
+impl Default for HotFilterOptions {
+    fn default() -> HotFilterOptions {
+        HotFilterOptions {
+            capacity: 0,
+            promote_after: 8,
+        }
+    }
+}
+
+/// Number of buckets listed in `ListStats::largest_buckets`.
+pub const LARGEST_BUCKETS: usize = 10;
+
+/// Shape of one list of a `Blocker`, see `Blocker::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListStats {
+    /// Distinct filters
+    pub filters: usize,
+    /// Filters summed over all buckets, a filter with several alternative tokens is in several buckets
+    pub bucket_entries: usize,
+    pub buckets: usize,
+    /// Token and number of filters of the largest buckets, largest first
+    pub largest_buckets: Vec<(Hash, usize)>,
+    /// Filters matched with a regex
+    pub regex_filters: usize,
+    /// Regexes compiled so far, they are compiled the first time a filter is checked
+    pub compiled_regexes: usize,
+    /// Length of the patterns of the compiled regexes. The `regex` crate does not
+    /// tell the size of its compiled programs, which grows with the pattern.
+    pub compiled_regex_bytes: usize,
+}
+
+/// Shape and footprint of a `Blocker`, see `Blocker::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockerStats {
+    pub csp: ListStats,
+    pub exceptions: ListStats,
+    pub importants: ListStats,
+    pub redirects: ListStats,
+    pub filters_tagged: ListStats,
+    pub filters: ListStats,
+    /// Size of the names, content types and data of the redirect resources
+    pub resource_bytes: usize,
+    pub memory: MemoryReport,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct BlockerResult {
     pub matched: bool,
     pub explicit_cancel: bool,
     pub redirect: Option<String>,
     pub exception: Option<String>,
+    /// Rule of the matched filter, see `NetworkFilter::to_string`. For filters
+    /// fused by the optimizer, all of their rules joined with ` <+> `
     pub filter: Option<String>,
+    /// `list_id` of the matched filter, i.e. the list that blocked the request
+    pub filter_list: Option<String>,
+    /// `list_id` of the matched exception
+    pub exception_list: Option<String>,
 }
 
 impl Default for BlockerResult {
@@ -38,7 +112,9 @@ impl Default for BlockerResult {
             explicit_cancel: false,
             redirect: None,
             exception: None,
-            filter: None
+            filter: None,
+            filter_list: None,
+            exception_list: None,
         }
     }
 }
@@ -47,7 +123,14 @@ impl Default for BlockerResult {
 pub enum BlockerError {
     SerializationError,
     DeserializationError,
+    DeserializationVersionMismatch {
+        format_version: u32,
+        manifest_version: String,
+    },
     OptimizedFilterExistence,
+    /// A `$badfilter` of the new version of a list disables a filter of
+    /// another list that optimizations fused with others, see `Blocker::replace_list`
+    BadFilterTargetOptimized,
     BadFilterAddUnsupported,
     FilterExists,
     BlockerFilterError(FilterError),
@@ -90,9 +173,12 @@ pub struct Blocker {
     #[serde(skip_serializing, skip_deserializing)]
     tags_enabled: HashSet<String>,
     tagged_filters_all: Vec<NetworkFilter>,
+    // Like enabled tags, lists turned off are kept when deserializing
+    #[serde(skip_serializing, skip_deserializing)]
+    lists_disabled: HashSet<String>,
 
     #[serde(skip_serializing, skip_deserializing)]
-    hot_filters: NetworkFilterList,
+    hot_filters: RefCell<HotFilters>,
 
     debug: bool,
     enable_optimizations: bool,
@@ -101,9 +187,52 @@ pub struct Blocker {
 
     #[serde(default)]
     resources: Resources,
+    // Kept so that filters added later are bucketed the same way
+    #[serde(default)]
+    token_profile: Option<TokenProfile>,
+    // `$badfilter` filters, and the filters they disable, for `replace_list`
+    // to know which filters to disable or bring back when a list changes.
+    // Left empty unless the blocker was built from named lists.
+    #[serde(default)]
+    badfilters: Vec<NetworkFilter>,
+    #[serde(default)]
+    badfiltered: Vec<NetworkFilter>,
     #[cfg(feature = "object-pooling")]
     #[serde(skip_serializing, skip_deserializing)]
     pool: TokenPool,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    observer: Option<Arc<dyn BlockerObserver>>,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    regex_limits: RegexLimits,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    result_cache: RefCell<Option<LruCache<ResultCacheKey, BlockerResult>>>,
+}
+
+/// Everything about a request that the result of `Blocker::check` depends on.
+/// Changes to the blocker itself, e.g. to its tags or lists, clear the cache instead.
+#[derive(PartialEq, Eq, Hash)]
+struct ResultCacheKey {
+    url: String,
+    request_type: RequestType,
+    source_hostname_hashes: Option<Vec<Hash>>,
+    is_third_party: Option<bool>,
+    bug: Option<u32>,
+}
+
+impl ResultCacheKey {
+    fn new(request: &Request) -> ResultCacheKey {
+        ResultCacheKey {
+            // `$match-case` filters tell apart URLs differing in case
+            url: request.original_url().to_owned(),
+            request_type: request.request_type.clone(),
+            source_hostname_hashes: request.source_hostname_hashes.clone(),
+            is_third_party: request.is_third_party,
+            bug: request.bug,
+        }
+    }
 }
 
 impl Blocker {
@@ -116,6 +245,21 @@ impl Blocker {
             return BlockerResult::default();
         }
 
+        if self.result_cache.borrow().is_none() {
+            return self.check_uncached(request);
+        }
+        let key = ResultCacheKey::new(request);
+        if let Some(result) = self.result_cache.borrow_mut().as_mut().and_then(|cache| cache.get(&key)) {
+            return result.clone();
+        }
+        let result = self.check_uncached(request);
+        if let Some(cache) = self.result_cache.borrow_mut().as_mut() {
+            cache.put(key, result.clone());
+        }
+        result
+    }
+
+    fn check_uncached(&self, request: &Request) -> BlockerResult {
         lazy_static! {
             // only check for tags in tagged and exception rule buckets,
             // pass empty set for the rest
@@ -127,9 +271,6 @@ impl Blocker {
         // 2. redirection ($redirect=resource)
         // 3. normal filters
         // 4. exceptions
-        #[cfg(feature = "metrics")]
-        print!("importants\t");
-
         let mut request_tokens;
         #[cfg(feature = "object-pooling")]
         {
@@ -142,44 +283,47 @@ impl Blocker {
         request.get_tokens(&mut request_tokens);
 
         let filter = self
-            .importants
             // Don't look at tags by default, only for the tagged rule bucket
-            .check(request, &request_tokens, &NO_TAGS)
-            .or_else(|| {
-                #[cfg(feature = "metrics")]
-                print!("tagged\t");
-                self.filters_tagged.check(request, &request_tokens, &self.tags_enabled)
-            })
-            .or_else(|| {
-                #[cfg(feature = "metrics")]
-                print!("redirects\t");
-                self.redirects.check(request, &request_tokens, &NO_TAGS)
-            })
-            .or_else(|| {
-                #[cfg(feature = "metrics")]
-                print!("filters\t"); 
-                self.filters.check(request, &request_tokens, &NO_TAGS)
-            });
+            .check_list("importants", &self.importants, request, &request_tokens, &NO_TAGS)
+            .or_else(|| self.check_list("tagged", &self.filters_tagged, request, &request_tokens, &self.tags_enabled))
+            .or_else(|| self.check_list("redirects", &self.redirects, request, &request_tokens, &NO_TAGS));
+
+        // Filters that matched often are looked for in a small list of their own first
+        let hot_filter;
+        let filter = match filter {
+            Some(filter) => Some(filter),
+            None => {
+                hot_filter = {
+                    let hot_filters = self.hot_filters.borrow();
+                    if hot_filters.list.is_empty() {
+                        None
+                    } else {
+                        self.check_list("hot", &hot_filters.list, request, &request_tokens, &NO_TAGS).cloned()
+                    }
+                };
+                let filter = hot_filter.as_ref()
+                    .or_else(|| self.check_list("filters", &self.filters, request, &request_tokens, &NO_TAGS));
+                if let Some(filter) = filter {
+                    self.hot_filters.borrow_mut().record(filter, || self.filters.token_of(filter, request, &request_tokens));
+                }
+                filter
+            }
+        };
 
         let exception = filter.as_ref().and_then(|f| {
             // Set `bug` of request
             if !f.is_important() {
-                #[cfg(feature = "metrics")]
-                print!("exceptions\t");
                 if f.has_bug() {
                     let mut request_bug = request.clone();
                     request_bug.bug = f.bug;
-                    self.exceptions.check(&request_bug, &request_tokens, &self.tags_enabled)
+                    self.check_list("exceptions", &self.exceptions, &request_bug, &request_tokens, &self.tags_enabled)
                 } else {
-                    self.exceptions.check(request, &request_tokens, &self.tags_enabled)
+                    self.check_list("exceptions", &self.exceptions, request, &request_tokens, &self.tags_enabled)
                 }
             } else {
                 None
             }
         });
-        
-        #[cfg(feature = "metrics")]
-        println!();
 
         // only match redirects if we have them set up
         let redirect: Option<String> = filter.as_ref().and_then(|f| {
@@ -195,7 +339,11 @@ impl Blocker {
                     Some(data_url.trim().to_owned())
                 } else {
                     // TOOD: handle error - throw?
-                    if self.debug {
+                    if let Some(observer) = self.observer.as_ref() {
+                        observer.on_event(&BlockerEvent::RedirectResourceMissing {
+                            redirect: redirect.to_string(),
+                        });
+                    } else if self.debug {
                         eprintln!("Matched rule with redirect option but did not find corresponding resource to send");
                     }
                     None
@@ -212,9 +360,16 @@ impl Blocker {
             redirect,
             exception: exception.as_ref().map(|f| f.to_string()), // copy the exception
             filter: filter.as_ref().map(|f| f.to_string()),       // copy the filter
+            filter_list: filter.as_ref().and_then(|f| f.list_id.as_deref()).map(String::from),
+            exception_list: exception.as_ref().and_then(|f| f.list_id.as_deref()).map(String::from),
         }
     }
 
+    fn check_list<'a>(&self, list_name: &'static str, list: &'a NetworkFilterList, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Option<&'a Arc<NetworkFilter>> {
+        let progress = CheckProgress::new(&self.regex_limits, self.observer.as_deref());
+        list.check(list_name, request, request_tokens, active_tags, &self.lists_disabled, progress)
+    }
+
     /**
      * Given a "main_frame" request, check if some content security policies
      * should be injected in the page.
@@ -224,6 +379,15 @@ impl Blocker {
     }
 
     pub fn new(network_filters: Vec<NetworkFilter>, options: &BlockerOptions) -> Blocker {
+        Blocker::new_with_token_profile(network_filters, options, None)
+    }
+
+    /**
+     * Same as `new`, but stores each filter under the token found in the fewest
+     * requests of `token_profile`. The profile is kept, also when serialized,
+     * and used for filters added later.
+     */
+    pub fn new_with_token_profile(network_filters: Vec<NetworkFilter>, options: &BlockerOptions, token_profile: Option<TokenProfile>) -> Blocker {
         // Capacity of filter subsets estimated based on counts in EasyList and EasyPrivacy - if necessary
         // the Vectors will grow beyond the pre-set capacity, but it is more efficient to allocate all at once
         // $csp=
@@ -237,7 +401,9 @@ impl Blocker {
         // $tag=
         let mut tagged_filters_all = Vec::with_capacity(200);
         // $badfilter
-        let mut badfilters = Vec::with_capacity(100);
+        let mut badfilters = Vec::new();
+        // Filters disabled by a $badfilter
+        let mut badfiltered = Vec::new();
         // All other filters
         let mut filters = Vec::with_capacity(network_filters.len());
 
@@ -245,18 +411,29 @@ impl Blocker {
         // TODO: resource handling
 
         if !network_filters.is_empty() && options.load_network_filters {
-            for filter in network_filters.iter() {
+            // Only filters from lists, see `NetworkFilter::list_id`, can be
+            // replaced later on, there is no need to remember bad filters otherwise
+            let keep_badfilters = network_filters.iter().any(|f| f.list_id.is_some());
+            let badfilter_ids: HashSet<Hash> = network_filters.iter()
+                .filter(|f| f.is_badfilter())
+                .map(|f| f.get_id_without_badfilter())
+                .collect();
+            let mut interner = Interner::new();
+            for mut filter in network_filters {
+                // set aside any bad filters
                 if filter.is_badfilter() {
-                    badfilters.push(filter);
+                    if keep_badfilters {
+                        badfilters.push(filter);
+                    }
+                    continue;
                 }
-            }
-            let badfilter_ids: HashSet<Hash> = badfilters.iter().map(|f| f.get_id_without_badfilter()).collect();
-            for filter in network_filters {
-                // skip any bad filters
-                let filter_id = filter.get_id();
-                if badfilter_ids.contains(&filter_id) || filter.is_badfilter() {
+                if badfilter_ids.contains(&filter.get_id()) {
+                    if keep_badfilters {
+                        badfiltered.push(filter);
+                    }
                     continue;
                 }
+                interner.intern(&mut filter);
                 if filter.is_csp() {
                     csp.push(filter);
                 } else if filter.is_exception() {
@@ -279,18 +456,21 @@ impl Blocker {
         redirects.shrink_to_fit();
         tagged_filters_all.shrink_to_fit();
         filters.shrink_to_fit();
+        badfilters.shrink_to_fit();
         
+        let profile = token_profile.as_ref();
         Blocker {
-            csp: NetworkFilterList::new(csp, options.enable_optimizations),
-            exceptions: NetworkFilterList::new(exceptions, options.enable_optimizations),
-            importants: NetworkFilterList::new(importants, options.enable_optimizations),
-            redirects: NetworkFilterList::new(redirects, options.enable_optimizations),
+            csp: NetworkFilterList::new_with_token_profile(csp, options.enable_optimizations, profile),
+            exceptions: NetworkFilterList::new_with_token_profile(exceptions, options.enable_optimizations, profile),
+            importants: NetworkFilterList::new_with_token_profile(importants, options.enable_optimizations, profile),
+            redirects: NetworkFilterList::new_with_token_profile(redirects, options.enable_optimizations, profile),
             filters_tagged: NetworkFilterList::new(Vec::new(), options.enable_optimizations),
-            filters: NetworkFilterList::new(filters, options.enable_optimizations),
+            filters: NetworkFilterList::new_with_token_profile(filters, options.enable_optimizations, profile),
             // Tags special case for enabling/disabling them dynamically
             tags_enabled: HashSet::new(),
             tagged_filters_all,
-            hot_filters: NetworkFilterList::default(),
+            lists_disabled: HashSet::new(),
+            hot_filters: RefCell::new(HotFilters::default()),
             // Options
             debug: options.debug,
             enable_optimizations: options.enable_optimizations,
@@ -298,8 +478,17 @@ impl Blocker {
             load_network_filters: options.load_network_filters,
 
             resources: Resources::default(),
+            token_profile,
+            badfilters,
+            badfiltered,
             #[cfg(feature = "object-pooling")]
             pool: TokenPool::default(),
+            #[cfg(not(feature = "metrics"))]
+            observer: None,
+            #[cfg(feature = "metrics")]
+            observer: Some(Arc::new(crate::observer::PrintObserver::default())),
+            regex_limits: RegexLimits::default(),
+            result_cache: RefCell::new(None),
         }
     }
 
@@ -320,32 +509,158 @@ impl Blocker {
     }
 
     pub fn filter_add(&mut self, filter: NetworkFilter) -> Result<&mut Blocker, BlockerError> {
+        self.clear_result_cache();
         if filter.is_badfilter() {
             Err(BlockerError::BadFilterAddUnsupported)
         } else if self.filter_exists(&filter) == Ok(true) {
             Err(BlockerError::FilterExists)
         } else if filter.is_csp() {
-            self.csp.filter_add(filter);
+            self.csp.filter_add(filter, self.token_profile.as_ref());
             Ok(self)
         } else if filter.is_exception() {
-            self.exceptions.filter_add(filter);
+            self.exceptions.filter_add(filter, self.token_profile.as_ref());
             Ok(self)
         } else if filter.is_important() {
-            self.importants.filter_add(filter);
+            self.importants.filter_add(filter, self.token_profile.as_ref());
             Ok(self)
         } else if filter.is_redirect() {
-            self.redirects.filter_add(filter);
+            self.redirects.filter_add(filter, self.token_profile.as_ref());
             Ok(self)
         } else if filter.tag.is_some() {
             self.tagged_filters_all.push(filter);
             let tags_enabled = HashSet::from_iter(self.tags_enabled().into_iter());
             Ok(self.tags_with_set(tags_enabled))
         } else {
-            self.filters.filter_add(filter);
+            self.filters.filter_add(filter, self.token_profile.as_ref());
             Ok(self)
         }
     }
 
+    /**
+     * Replaces the filters of list `list_id`, see `NetworkFilter::list_id`, with
+     * `network_filters`, e.g. a newer version of the list. Unlike building a new
+     * blocker, only the buckets holding filters of the list are rebuilt.
+     *
+     * `$badfilter` filters apply across lists: filters of other lists disabled by
+     * the old version of the list are brought back, and those disabled by the new
+     * one are taken out. Fails with `BlockerError::BadFilterTargetOptimized`,
+     * leaving the blocker as it was, if such a filter was fused with others by
+     * optimizations; building the blocker again is then the only option.
+     */
+    pub fn replace_list(&mut self, list_id: &str, network_filters: Vec<NetworkFilter>) -> Result<&mut Blocker, BlockerError> {
+        let in_list = |filter: &NetworkFilter| filter.list_id.as_deref() == Some(list_id);
+        let (new_badfilters, network_filters): (Vec<_>, Vec<_>) = network_filters
+            .into_iter()
+            .partition(|filter| filter.is_badfilter());
+
+        let other_badfilter_ids: HashSet<Hash> = self.badfilters.iter()
+            .filter(|f| !in_list(f))
+            .map(|f| f.get_id_without_badfilter())
+            .collect();
+        let old_badfilter_ids: HashSet<Hash> = self.badfilters.iter()
+            .filter(|f| in_list(f))
+            .map(|f| f.get_id_without_badfilter())
+            .collect();
+        // Filters of other lists that the new version of the list disables
+        let added_badfilters: Vec<&NetworkFilter> = new_badfilters.iter()
+            .filter(|f| {
+                let id = f.get_id_without_badfilter();
+                !old_badfilter_ids.contains(&id) && !other_badfilter_ids.contains(&id)
+            })
+            .collect();
+        let added_ids: HashSet<Hash> = added_badfilters.iter().map(|f| f.get_id_without_badfilter()).collect();
+        let lists = [&self.csp, &self.exceptions, &self.importants, &self.redirects, &self.filters];
+        let fused_target = lists.iter()
+            .flat_map(|list| list.filters())
+            .map(|filter| &**filter)
+            .chain(self.tagged_filters_all.iter())
+            .filter(|filter| !in_list(filter))
+            .any(|filter| added_badfilters.iter().any(|badfilter| may_fuse_badfiltered(filter, badfilter)));
+        if fused_target {
+            return Err(BlockerError::BadFilterTargetOptimized);
+        }
+
+        self.clear_result_cache();
+        // Hot filters hold on to filters that may be removed
+        let hot_filter_options = self.hot_filter_options();
+        self.with_hot_filters(hot_filter_options);
+
+        let mut badfilter_ids = other_badfilter_ids;
+        badfilter_ids.extend(new_badfilters.iter().map(|f| f.get_id_without_badfilter()));
+        self.badfilters.retain(|f| !in_list(f));
+        self.badfilters.extend(new_badfilters);
+
+        // Filters of other lists no longer disabled, and the new filters of the list
+        let mut additions = Vec::new();
+        let mut badfiltered = Vec::new();
+        for filter in std::mem::take(&mut self.badfiltered) {
+            if in_list(&filter) {
+                continue;
+            }
+            if badfilter_ids.contains(&filter.get_id()) {
+                badfiltered.push(filter);
+            } else {
+                additions.push(filter);
+            }
+        }
+        let mut interner = Interner::new();
+        for mut filter in network_filters {
+            if badfilter_ids.contains(&filter.get_id()) {
+                badfiltered.push(filter);
+            } else {
+                interner.intern(&mut filter);
+                additions.push(filter);
+            }
+        }
+
+        let mut csp = Vec::new();
+        let mut exceptions = Vec::new();
+        let mut importants = Vec::new();
+        let mut redirects = Vec::new();
+        let mut filters = Vec::new();
+        let mut tagged = Vec::new();
+        for filter in additions {
+            if filter.is_csp() {
+                csp.push(filter);
+            } else if filter.is_exception() {
+                exceptions.push(filter);
+            } else if filter.is_important() {
+                importants.push(filter);
+            } else if filter.is_redirect() {
+                redirects.push(filter);
+            } else if filter.tag.is_some() {
+                tagged.push(filter);
+            } else {
+                filters.push(filter);
+            }
+        }
+
+        let remove = |filter: &NetworkFilter| in_list(filter) || added_ids.contains(&filter.get_id());
+        let profile = self.token_profile.as_ref();
+        let optimize = self.enable_optimizations;
+        let mut removed = Vec::new();
+        removed.extend(self.csp.replace_filters(remove, csp, optimize, profile));
+        removed.extend(self.exceptions.replace_filters(remove, exceptions, optimize, profile));
+        removed.extend(self.importants.replace_filters(remove, importants, optimize, profile));
+        removed.extend(self.redirects.replace_filters(remove, redirects, optimize, profile));
+        removed.extend(self.filters.replace_filters(remove, filters, optimize, profile));
+        for filter in std::mem::take(&mut self.tagged_filters_all) {
+            if remove(&filter) {
+                removed.push(filter.into());
+            } else {
+                self.tagged_filters_all.push(filter);
+            }
+        }
+        self.tagged_filters_all.extend(tagged);
+        badfiltered.extend(removed.into_iter()
+            .filter(|filter| !in_list(filter))
+            .map(|filter| Arc::try_unwrap(filter).unwrap_or_else(|filter| (*filter).clone())));
+        self.badfiltered = badfiltered;
+
+        let tags_enabled = self.tags_enabled.clone();
+        Ok(self.tags_with_set(tags_enabled))
+    }
+
     pub fn with_tags<'a>(&'a mut self, tags: &[&str]) -> &'a mut Blocker {
         let tag_set: HashSet<String> = HashSet::from_iter(tags.iter().map(|&t| String::from(t)));
         self.tags_with_set(tag_set)
@@ -368,42 +683,480 @@ impl Blocker {
     }
 
     fn tags_with_set<'a>(&'a mut self, tags_enabled: HashSet<String>) -> &'a mut Blocker {
+        self.clear_result_cache();
         self.tags_enabled = tags_enabled;
         let filters: Vec<NetworkFilter> = self.tagged_filters_all.iter()
-            .filter(|n| n.tag.is_some() && self.tags_enabled.contains(n.tag.as_ref().unwrap()))
+            .filter(|n| n.tag.is_some() && self.tags_enabled.contains(n.tag.as_deref().unwrap()))
             .cloned()
             .collect();
-        self.filters_tagged = NetworkFilterList::new(filters, self.enable_optimizations);
+        self.filters_tagged = NetworkFilterList::new_with_token_profile(filters, self.enable_optimizations, self.token_profile.as_ref());
         self
     }
 
     pub fn tags_enabled(&self) -> Vec<String> {
         self.tags_enabled.iter().cloned().collect()
     }
+
+    /**
+     * Stops filters of the given lists, by their `NetworkFilter::list_id`, from
+     * matching, until they are enabled again with `lists_enable`. Unlike tags,
+     * lists are enabled unless disabled, and turning them on and off does not
+     * rebuild anything.
+     */
+    pub fn lists_disable<'a>(&'a mut self, lists: &[&str]) -> &'a mut Blocker {
+        self.clear_result_cache();
+        self.lists_disabled.extend(lists.iter().map(|&l| String::from(l)));
+        self
+    }
+
+    pub fn lists_enable<'a>(&'a mut self, lists: &[&str]) -> &'a mut Blocker {
+        self.clear_result_cache();
+        for list in lists {
+            self.lists_disabled.remove(*list);
+        }
+        self
+    }
+
+    pub fn lists_disabled(&self) -> Vec<String> {
+        self.lists_disabled.iter().cloned().collect()
+    }
     
     pub fn with_resources(&mut self, resources: Resources) -> &mut Blocker {
+        self.clear_result_cache();
         self.resources = resources;
         self
     }
 
     pub fn resource_add(&mut self, key: String, resource: Resource) -> &mut Blocker {
+        self.clear_result_cache();
         self.resources.add_resource(key, resource);
         self
     }
 
+    /**
+     * Makes filters share equal hostnames, redirects, csp values, tags and
+     * domain lists. Filters are interned when the blocker is built and
+     * deserialized; this is only needed to also share the data of filters
+     * added with `filter_add` since.
+     */
+    pub fn compact(&mut self) {
+        let mut interner = Interner::new();
+        for list in [
+            &mut self.csp,
+            &mut self.exceptions,
+            &mut self.importants,
+            &mut self.redirects,
+            &mut self.filters_tagged,
+            &mut self.filters,
+        ].iter_mut() {
+            list.intern(&mut interner);
+        }
+        for filter in self.tagged_filters_all.iter_mut() {
+            interner.intern(filter);
+        }
+    }
+
+    /**
+     * Number of filters, buckets and regexes of each list, the largest buckets
+     * and memory used. Tagged filters only count once their tag is enabled, and
+     * buckets of an engine loaded with `deserialize_indexed` once decoded.
+     */
+    pub fn stats(&self) -> BlockerStats {
+        BlockerStats {
+            csp: self.csp.stats(),
+            exceptions: self.exceptions.stats(),
+            importants: self.importants.stats(),
+            redirects: self.redirects.stats(),
+            filters_tagged: self.filters_tagged.stats(),
+            filters: self.filters.stats(),
+            resource_bytes: self.resources.resources
+                .iter()
+                .map(|(name, resource)| name.len() + resource.content_type.len() + resource.data.len())
+                .sum(),
+            memory: self.memory_report(),
+        }
+    }
+
+    /**
+     * Approximate memory used by the filters of each list, with and without
+     * the sharing done by `compact`. Buckets of an engine loaded with
+     * `deserialize_indexed` only count once they have been decoded.
+     */
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut counter = MemoryCounter::default();
+        let csp = self.csp.memory(&mut counter);
+        let exceptions = self.exceptions.memory(&mut counter);
+        let importants = self.importants.memory(&mut counter);
+        let redirects = self.redirects.memory(&mut counter);
+
+        // All tagged filters are kept, the list only holds those of enabled tags
+        let mut filters_tagged = ListMemory::default();
+        let bytes = self.tagged_filters_all.capacity() * size_of::<NetworkFilter>();
+        filters_tagged.bytes += bytes;
+        filters_tagged.unshared_bytes += bytes;
+        for filter in self.tagged_filters_all.iter() {
+            counter.add_filter(&mut filters_tagged, filter);
+        }
+        let enabled = self.filters_tagged.memory(&mut counter);
+        filters_tagged.bytes += enabled.bytes;
+        filters_tagged.unshared_bytes += enabled.unshared_bytes;
+
+        MemoryReport {
+            csp,
+            exceptions,
+            importants,
+            redirects,
+            filters_tagged,
+            filters: self.filters.memory(&mut counter),
+        }
+    }
+
+    pub fn token_profile(&self) -> Option<&TokenProfile> {
+        self.token_profile.as_ref()
+    }
+
     pub fn resource_get(&self, key: &str) -> Option<&Resource> {
         self.resources.get_resource(key)
     }
+
+    pub fn with_observer(&mut self, observer: Arc<dyn BlockerObserver>) -> &mut Blocker {
+        self.observer = Some(observer);
+        self.observe_lazy_buckets();
+        self
+    }
+
+    /// Passes the observer on to the lists loaded from the indexed layout.
+    fn observe_lazy_buckets(&mut self) {
+        if let Some(observer) = self.observer.as_ref() {
+            for list in [&mut self.csp, &mut self.exceptions, &mut self.importants, &mut self.redirects, &mut self.filters].iter_mut() {
+                list.with_observer(observer);
+            }
+        }
+    }
+
+    pub fn observer(&self) -> Option<Arc<dyn BlockerObserver>> {
+        self.observer.clone()
+    }
+
+    /// Sets the limits of the regexes this blocker compiles from now on.
+    /// Regexes that were already compiled are not affected.
+    pub fn with_regex_limits(&mut self, limits: RegexLimits) -> &mut Blocker {
+        self.regex_limits = limits;
+        self
+    }
+
+    pub fn regex_limits(&self) -> RegexLimits {
+        self.regex_limits
+    }
+
+    /// Changes the tuning of the hot filter cache, emptying it.
+    pub fn with_hot_filters(&mut self, options: HotFilterOptions) -> &mut Blocker {
+        self.hot_filters = RefCell::new(HotFilters {
+            options,
+            ..HotFilters::default()
+        });
+        self
+    }
+
+    pub fn hot_filter_options(&self) -> HotFilterOptions {
+        self.hot_filters.borrow().options
+    }
+
+    /**
+     * Keeps the results of the last `capacity` distinct requests, returning them
+     * again for the same URL, type and source without rescanning the filters. The
+     * cache is emptied whenever filters, enabled tags or resources change. A
+     * `capacity` of 0, the default, disables it.
+     */
+    pub fn with_result_cache(&mut self, capacity: usize) -> &mut Blocker {
+        self.result_cache = RefCell::new(if capacity > 0 { Some(LruCache::new(capacity)) } else { None });
+        self
+    }
+
+    pub fn result_cache_capacity(&self) -> usize {
+        self.result_cache.borrow().as_ref().map(|cache| cache.cap()).unwrap_or(0)
+    }
+
+    fn clear_result_cache(&mut self) {
+        if let Some(cache) = self.result_cache.get_mut() {
+            cache.clear();
+        }
+    }
+
+    /**
+     * Checks whether requests to `hostname` are blocked by a `||hostname^` filter,
+     * matching it or one of its parent domains, whatever their type or source.
+     * Only filters without options restricting where they apply can block, while
+     * any such exception prevents it, unless the blocking filter is `$important`.
+     * Other filters, e.g. on URL paths, are not taken into account.
+     */
+    pub fn is_hostname_blocked(&self, hostname: &str) -> bool {
+        if !self.load_network_filters {
+            return false;
+        }
+        let hostname = hostname.to_lowercase();
+        let enabled = |filter: &&Arc<NetworkFilter>| is_enabled(filter, &self.tags_enabled, &self.lists_disabled);
+        let applies_everywhere = |filter: &Arc<NetworkFilter>| {
+            filter.mask.contains(NetworkFilterMask::DEFAULT_OPTIONS)
+                && filter.opt_domains.is_none()
+                && filter.opt_not_domains.is_none()
+                && filter.redirect.is_none()
+                && filter.bug.is_none()
+                && !filter.is_csp()
+                && !filter.is_badfilter()
+        };
+
+        if self.importants.hostname_filters(&hostname).filter(enabled).any(applies_everywhere) {
+            return true;
+        }
+        if self.exceptions.hostname_filters(&hostname).filter(enabled).any(applies_everywhere) {
+            return false;
+        }
+        let blocked = self.filters.hostname_filters(&hostname)
+            .chain(self.filters_tagged.hostname_filters(&hostname))
+            .filter(enabled)
+            .any(applies_everywhere);
+        blocked
+    }
+
+    /**
+     * Compiles the regexes of all filters up front rather than on their first
+     * match, avoiding latency spikes on the first requests. Returns the filters
+     * whose regex failed to compile; these never match. Failures are also
+     * reported to the observer, if any.
+     */
+    pub fn warm_up_regexes(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut failed = Vec::new();
+        let lists = [&self.csp, &self.exceptions, &self.importants, &self.redirects, &self.filters_tagged, &self.filters];
+        for filter in lists.iter().flat_map(|list| list.filters()) {
+            if !(filter.is_regex() || filter.is_complete_regex()) || !seen.insert(filter.id) {
+                continue;
+            }
+            if let CompiledRegex::RegexParsingError(e) = &*filter.get_regex_with_limits(&self.regex_limits) {
+                if let Some(observer) = self.observer.as_ref() {
+                    observer.on_event(&BlockerEvent::RegexCompileFailed {
+                        filter: filter.to_string(),
+                        error: e.to_string(),
+                    });
+                }
+                failed.push(filter.to_string());
+            }
+        }
+        failed
+    }
+
+    /// Writes the buckets of every list to `body` and returns the metadata
+    /// needed to find them again, see `serialization::serialize_indexed`.
+    pub(crate) fn write_indexed(&self, body: &mut Vec<u8>) -> Result<IndexedMetadata<IndexedParts>, BlockerError> {
+        let mut lists = Vec::with_capacity(10);
+        for list in [&self.csp, &self.exceptions, &self.importants, &self.redirects, &self.filters].iter() {
+            let (filters, hostnames) = list.write_indexed(body)?;
+            lists.push(filters);
+            lists.push(hostnames);
+        }
+        Ok(IndexedMetadata {
+            manifest_version: crate::serialization::VERSION.to_owned(),
+            lists,
+            blocker: IndexedParts {
+                tagged_filters_all: self.tagged_filters_all.clone(),
+                debug: self.debug,
+                enable_optimizations: self.enable_optimizations,
+                load_cosmetic_filters: self.load_cosmetic_filters,
+                load_network_filters: self.load_network_filters,
+                resources: self.resources.clone(),
+                token_profile: self.token_profile.clone(),
+                badfilters: self.badfilters.clone(),
+                badfiltered: self.badfiltered.clone(),
+            },
+        })
+    }
+
+    /// Recreates a `Blocker` whose lists decode their buckets from `data` on demand.
+    pub(crate) fn from_indexed(metadata: IndexedMetadata<IndexedParts>, data: &std::rc::Rc<dyn AsRef<[u8]>>, body_start: usize) -> Result<Blocker, BlockerError> {
+        let lists = metadata.lists;
+        if lists.len() != 10 {
+            return Err(BlockerError::DeserializationError);
+        }
+        let lazy_list = |i: usize| -> Result<NetworkFilterList, BlockerError> {
+            Ok(NetworkFilterList {
+                filter_map: HashMap::new(),
+                hostname_map: HashMap::new(),
+                lazy: Some(LazyBuckets::new(data, body_start, lists[2 * i])?),
+                lazy_hostnames: Some(LazyBuckets::new(data, body_start, lists[2 * i + 1])?),
+                #[cfg(feature = "multi-pattern-matching")]
+                plain_patterns: Default::default(),
+            })
+        };
+        let parts = metadata.blocker;
+        let options = BlockerOptions {
+            debug: parts.debug,
+            enable_optimizations: parts.enable_optimizations,
+            load_cosmetic_filters: parts.load_cosmetic_filters,
+            load_network_filters: parts.load_network_filters,
+        };
+
+        let mut blocker = Blocker::new(Vec::new(), &options);
+        blocker.csp = lazy_list(0)?;
+        blocker.exceptions = lazy_list(1)?;
+        blocker.importants = lazy_list(2)?;
+        blocker.redirects = lazy_list(3)?;
+        blocker.filters = lazy_list(4)?;
+        blocker.tagged_filters_all = parts.tagged_filters_all;
+        blocker.resources = parts.resources;
+        blocker.token_profile = parts.token_profile;
+        blocker.badfilters = parts.badfilters;
+        blocker.badfiltered = parts.badfiltered;
+        blocker.observe_lazy_buckets();
+        Ok(blocker)
+    }
 }
 
-#[derive(Serialize, Deserialize, Default)]
+/// Parts of a `Blocker` stored in the metadata section of the indexed layout.
+/// Tagged filters are kept there since `filters_tagged` is rebuilt whenever tags change.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IndexedParts {
+    tagged_filters_all: Vec<NetworkFilter>,
+    debug: bool,
+    enable_optimizations: bool,
+    load_cosmetic_filters: bool,
+    load_network_filters: bool,
+    resources: Resources,
+    #[serde(default)]
+    token_profile: Option<TokenProfile>,
+    #[serde(default)]
+    badfilters: Vec<NetworkFilter>,
+    #[serde(default)]
+    badfiltered: Vec<NetworkFilter>,
+}
+
+/**
+ * Adaptive cache of the filters of the main list which match most often.
+ * Matches are counted per filter, and counts are halved every
+ * `capacity * promote_after` matches so that filters which stop matching
+ * can be replaced. A filter reaching `promote_after` is promoted, evicting
+ * the promoted filter with the lowest count if the cache is full.
+ */
+#[derive(Default)]
+struct HotFilters {
+    options: HotFilterOptions,
+    list: NetworkFilterList,
+    // Promoted filters, with the token of their bucket in the main list
+    promoted: Vec<(Hash, Arc<NetworkFilter>)>,
+    hits: HashMap<Hash, u32>,
+    recorded: u32,
+}
+
+impl HotFilters {
+    /// Counts a match of `filter`, promoting it under the token returned by `token` if it is hot enough.
+    fn record<F: FnOnce() -> Option<Hash>>(&mut self, filter: &Arc<NetworkFilter>, token: F) {
+        // A bug ID changes which exceptions apply, keep those filters in the main list only
+        if self.options.capacity == 0 || filter.has_bug() {
+            return;
+        }
+
+        let hits = self.hits.entry(filter.id).or_insert(0);
+        *hits += 1;
+        let hits = *hits;
+        if hits >= self.options.promote_after && !self.promoted.iter().any(|(_, f)| f.id == filter.id) {
+            // `||hostname^` filters are stored by hostname rather than token
+            let token = if filter.is_hostname_only() { Some(0) } else { token() };
+            if let Some(token) = token {
+                self.promote(token, filter, hits);
+            }
+        }
+
+        self.recorded += 1;
+        if self.recorded >= (self.options.capacity as u32).saturating_mul(self.options.promote_after) {
+            self.recorded = 0;
+            self.hits.values_mut().for_each(|hits| *hits /= 2);
+            self.hits.retain(|_, hits| *hits > 0);
+        }
+    }
+
+    fn promote(&mut self, token: Hash, filter: &Arc<NetworkFilter>, hits: u32) {
+        if self.promoted.len() < self.options.capacity {
+            self.promoted.push((token, Arc::clone(filter)));
+        } else {
+            let hits_of = |f: &Arc<NetworkFilter>| self.hits.get(&f.id).cloned().unwrap_or(0);
+            let coldest = self.promoted.iter()
+                .enumerate()
+                .min_by_key(|(_, (_, f))| hits_of(f))
+                .filter(|(_, (_, f))| hits_of(f) < hits)
+                .map(|(i, _)| i);
+            match coldest {
+                Some(i) => self.promoted[i] = (token, Arc::clone(filter)),
+                None => return,
+            }
+        }
+
+        // Keep filters under the same buckets as in the main list: fused filters
+        // have no tokens of their own, and would otherwise be checked for every request
+        let mut list = NetworkFilterList::default();
+        for (token, filter) in &self.promoted {
+            if filter.is_hostname_only() {
+                insert_dup(&mut list.hostname_map, hostname_key(filter), Arc::clone(filter));
+            } else {
+                insert_dup(&mut list.filter_map, *token, Arc::clone(filter));
+            }
+        }
+        self.list = list;
+    }
+}
+
+#[derive(Deserialize, Default)]
 struct NetworkFilterList {
     filter_map: HashMap<Hash, Vec<Arc<NetworkFilter>>>,
+    // `||hostname^` filters, keyed by the hash of their hostname. A request
+    // only needs to look up its own hostname and each of its parent domains.
+    #[serde(default)]
+    hostname_map: HashMap<Hash, Vec<Arc<NetworkFilter>>>,
+    // Buckets of a list loaded from the indexed layout. Filters added
+    // afterwards still go to `filter_map` and `hostname_map`, lookups consult both.
+    #[serde(skip)]
+    lazy: Option<LazyBuckets>,
+    #[serde(skip)]
+    lazy_hostnames: Option<LazyBuckets>,
+    // Plain patterns of each token bucket, built the first time the bucket is checked.
+    #[cfg(feature = "multi-pattern-matching")]
+    #[serde(skip)]
+    plain_patterns: std::cell::RefCell<HashMap<Hash, Option<std::rc::Rc<PlainPatterns>>>>,
     // optimized: Option<bool>
 }
 
+impl Serialize for NetworkFilterList {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        struct SortedMap<'a>(&'a HashMap<Hash, Vec<Arc<NetworkFilter>>>);
+        impl<'a> Serialize for SortedMap<'a> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                crate::serialization::serialize_sorted_map(self.0, serializer)
+            }
+        }
+
+        let mut state = serializer.serialize_struct("NetworkFilterList", 2)?;
+        state.serialize_field("filter_map", &SortedMap(&merge_lazy(&self.filter_map, self.lazy.as_ref())))?;
+        state.serialize_field("hostname_map", &SortedMap(&merge_lazy(&self.hostname_map, self.lazy_hostnames.as_ref())))?;
+        state.end()
+    }
+}
+
 impl NetworkFilterList {
     pub fn new(filters: Vec<NetworkFilter>, enable_optimizations: bool) -> NetworkFilterList {
+        NetworkFilterList::new_with_token_profile(filters, enable_optimizations, None)
+    }
+
+    pub fn new_with_token_profile(filters: Vec<NetworkFilter>, enable_optimizations: bool, profile: Option<&TokenProfile>) -> NetworkFilterList {
+        let (hostname_filters, filters): (Vec<_>, Vec<_>) = filters
+            .into_iter()
+            .partition(|filter| filter.is_hostname_only());
+
+        let mut hostname_map = HashMap::with_capacity(hostname_filters.len());
+        for filter in hostname_filters.into_iter().map(Arc::new) {
+            insert_dup(&mut hostname_map, hostname_key(&filter), filter);
+        }
+
         // Compute tokens for all filters
         let filter_tokens: Vec<_> = filters
             .into_iter()
@@ -413,28 +1166,14 @@ impl NetworkFilterList {
             })
             .collect();
         // compute the tokens' frequency histogram
-        let (total_number_of_tokens, tokens_histogram) = token_histogram(&filter_tokens);
+        let (_, tokens_histogram) = token_histogram(&filter_tokens);
 
         // Build a HashMap of tokens to Network Filters (held through Arc, Atomic Reference Counter)
         let mut filter_map = HashMap::with_capacity(filter_tokens.len());
         {
             for (filter_pointer, multi_tokens) in filter_tokens {
                 for tokens in multi_tokens {
-                    let mut best_token: Hash = 0;
-                    let mut min_count = total_number_of_tokens + 1;
-                    for token in tokens {
-                        match tokens_histogram.get(&token) {
-                            None => {
-                                min_count = 0;
-                                best_token = token
-                            }
-                            Some(&count) if count < min_count => {
-                                min_count = count;
-                                best_token = token
-                            }
-                            _ => {}
-                        }
-                    }
+                    let best_token = best_token(&tokens, |token| tokens_histogram.get(token).map(|&count| count as usize), profile);
                     insert_dup(&mut filter_map, best_token, Arc::clone(&filter_pointer));
                 }
             }
@@ -442,76 +1181,244 @@ impl NetworkFilterList {
 
         // Update all values
         if enable_optimizations {
-            let mut optimized_map = HashMap::with_capacity(filter_map.len());
-            for (key, filters) in filter_map {
-                let mut unoptimized: Vec<NetworkFilter> = Vec::with_capacity(filters.len());
-                let mut unoptimizable: Vec<Arc<NetworkFilter>> = Vec::with_capacity(filters.len());
-                for f in filters {
-                    match Arc::try_unwrap(f) {
-                        Ok(f) => unoptimized.push(f),
-                        Err(af) => unoptimizable.push(af)
-                    }
-                }
-
-                let mut optimized: Vec<_> = if unoptimized.len() > 1 {
-                    optimizer::optimize(unoptimized).into_iter().map(Arc::new).collect()
-                } else {
-                    // nothing to optimize
-                    unoptimized.into_iter().map(Arc::new).collect()
-                };
-                
-                optimized.append(&mut unoptimizable);
-                optimized_map.insert(key, optimized);
-            }
-
-            // won't mutate anymore, shrink to fit items
-            optimized_map.shrink_to_fit();
-
             NetworkFilterList {
-                filter_map: optimized_map,
+                filter_map: optimize_buckets(filter_map),
+                hostname_map: optimize_buckets(hostname_map),
+                lazy: None,
+                lazy_hostnames: None,
+                #[cfg(feature = "multi-pattern-matching")]
+                plain_patterns: Default::default(),
                 // optimized: Some(enable_optimizations)
             }
         } else {
             filter_map.shrink_to_fit();
-            NetworkFilterList { 
+            hostname_map.shrink_to_fit();
+            NetworkFilterList {
                 filter_map,
+                hostname_map,
+                lazy: None,
+                lazy_hostnames: None,
+                #[cfg(feature = "multi-pattern-matching")]
+                plain_patterns: Default::default(),
                 // optimized: Some(enable_optimizations)
             }
         }
     }
 
-    pub fn filter_add(&mut self, filter: NetworkFilter) -> &mut NetworkFilterList {
+    fn write_indexed(&self, body: &mut Vec<u8>) -> Result<(BucketIndex, BucketIndex), BlockerError> {
+        let filters = crate::serialization::write_buckets(body, &merge_lazy(&self.filter_map, self.lazy.as_ref()))?;
+        let hostnames = crate::serialization::write_buckets(body, &merge_lazy(&self.hostname_map, self.lazy_hostnames.as_ref()))?;
+        Ok((filters, hostnames))
+    }
+
+    /// Every filter in this list, including lazily loaded ones. A filter can be
+    /// returned more than once if it is stored under multiple tokens.
+    fn filters(&self) -> impl Iterator<Item = &Arc<NetworkFilter>> {
+        let lazy = self.lazy.iter()
+            .chain(self.lazy_hostnames.iter())
+            .flat_map(|lazy| lazy.iter())
+            .flat_map(|(_, filters)| filters);
+        self.filter_map.values()
+            .chain(self.hostname_map.values())
+            .flatten()
+            .chain(lazy)
+    }
+
+    /// Token of the first bucket in which `check` finds `filter` for this request.
+    fn token_of(&self, filter: &Arc<NetworkFilter>, request: &Request, request_tokens: &[Hash]) -> Option<Hash> {
+        request.source_hostname_hashes.iter()
+            .flatten()
+            .chain(request_tokens.iter())
+            .find(|token| self.bucket(token).map(|mut filters| filters.any(|f| Arc::ptr_eq(f, filter))).unwrap_or(false))
+            .cloned()
+    }
+
+    fn with_observer(&mut self, observer: &Arc<dyn BlockerObserver>) {
+        for lazy in [&mut self.lazy, &mut self.lazy_hostnames].iter_mut().filter_map(|lazy| lazy.as_mut()) {
+            lazy.with_observer(Arc::clone(observer));
+        }
+    }
+
+    /// Filters stored under `token`, or `None` if there is no such bucket.
+    fn bucket<'a>(&'a self, token: &Hash) -> Option<impl Iterator<Item = &'a Arc<NetworkFilter>>> {
+        lookup(&self.filter_map, self.lazy.as_ref(), token)
+    }
+
+    /// `||hostname^` filters whose hostname hashes to `key`.
+    fn hostname_bucket<'a>(&'a self, key: &Hash) -> Option<impl Iterator<Item = &'a Arc<NetworkFilter>>> {
+        lookup(&self.hostname_map, self.lazy_hostnames.as_ref(), key)
+    }
+
+    /// `||hostname^` filters matching `hostname` itself or any of its parent domains,
+    /// ignoring their options.
+    fn hostname_filters<'a>(&'a self, hostname: &'a str) -> impl Iterator<Item = &'a Arc<NetworkFilter>> {
+        hostname_suffixes(hostname)
+            .filter_map(move |suffix| self.hostname_bucket(&fast_hash(suffix)).map(|bucket| (suffix, bucket)))
+            .flat_map(|(suffix, bucket)| bucket.filter(move |filter| filter.hostname.as_deref() == Some(suffix)))
+    }
+
+    fn is_empty(&self) -> bool {
+        let lazy_is_empty = |lazy: &Option<LazyBuckets>| lazy.as_ref().map(|lazy| lazy.len() == 0).unwrap_or(true);
+        self.filter_map.is_empty()
+            && self.hostname_map.is_empty()
+            && lazy_is_empty(&self.lazy)
+            && lazy_is_empty(&self.lazy_hostnames)
+    }
+
+    pub fn filter_add(&mut self, filter: NetworkFilter, profile: Option<&TokenProfile>) -> &mut NetworkFilterList {
         let filter_tokens = filter.get_tokens();
-        let total_rules = vec_hashmap_len(&self.filter_map);
         let filter_pointer = Arc::new(filter);
+        #[cfg(feature = "multi-pattern-matching")]
+        self.plain_patterns.get_mut().clear();
+
+        if filter_pointer.is_hostname_only() {
+            insert_dup(&mut self.hostname_map, hostname_key(&filter_pointer), filter_pointer);
+            return self;
+        }
 
         for tokens in filter_tokens {
-            let mut best_token: Hash = 0;
-            let mut min_count = total_rules + 1;
-            for token in tokens {
-                match self.filter_map.get(&token) {
-                    None => {
-                        min_count = 0;
-                        best_token = token
+            let best_token = best_token(&tokens, |token| self.bucket(token).map(|filters| filters.count()), profile);
+            insert_dup(&mut self.filter_map, best_token, Arc::clone(&filter_pointer));
+        }
+
+        self
+    }
+
+    /**
+     * Removes the filters for which `remove` is true and adds `filters`. Only
+     * the buckets that changed are optimized again, and buckets not decoded
+     * yet from the indexed layout are decoded first. Returns the filters removed.
+     */
+    fn replace_filters<F>(&mut self, remove: F, filters: Vec<NetworkFilter>, enable_optimizations: bool, profile: Option<&TokenProfile>) -> Vec<Arc<NetworkFilter>>
+    where
+        F: Fn(&NetworkFilter) -> bool,
+    {
+        if let Some(lazy) = self.lazy.take() {
+            self.filter_map = merge_lazy(&self.filter_map, Some(&lazy)).into_owned();
+        }
+        if let Some(lazy) = self.lazy_hostnames.take() {
+            self.hostname_map = merge_lazy(&self.hostname_map, Some(&lazy)).into_owned();
+        }
+        #[cfg(feature = "multi-pattern-matching")]
+        self.plain_patterns.get_mut().clear();
+
+        let mut removed: Vec<Arc<NetworkFilter>> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut changed_filters = HashSet::new();
+        let mut changed_hostnames = HashSet::new();
+        for (map, changed) in [(&mut self.filter_map, &mut changed_filters), (&mut self.hostname_map, &mut changed_hostnames)].iter_mut() {
+            for (token, bucket) in map.iter_mut() {
+                let len = bucket.len();
+                bucket.retain(|filter| {
+                    if !remove(filter) {
+                        return true;
                     }
-                    Some(filters) if filters.len() < min_count => {
-                        min_count = filters.len();
-                        best_token = token
+                    if seen.insert(Arc::as_ptr(filter) as usize) {
+                        removed.push(Arc::clone(filter));
                     }
-                    _ => {}
+                    false
+                });
+                if bucket.len() != len {
+                    changed.insert(*token);
                 }
             }
+            map.retain(|_, bucket| !bucket.is_empty());
+        }
 
-            insert_dup(&mut self.filter_map, best_token, Arc::clone(&filter_pointer));
+        for filter in filters {
+            let filter_tokens = filter.get_tokens();
+            let filter_pointer: Arc<NetworkFilter> = filter.into();
+            if filter_pointer.is_hostname_only() {
+                let key = hostname_key(&filter_pointer);
+                insert_dup(&mut self.hostname_map, key, filter_pointer);
+                changed_hostnames.insert(key);
+                continue;
+            }
+            for tokens in filter_tokens {
+                let best_token = best_token(&tokens, |token| self.filter_map.get(token).map(|filters| filters.len()), profile);
+                insert_dup(&mut self.filter_map, best_token, Arc::clone(&filter_pointer));
+                changed_filters.insert(best_token);
+            }
         }
 
-        self
+        if enable_optimizations {
+            for (map, changed) in [(&mut self.filter_map, &changed_filters), (&mut self.hostname_map, &changed_hostnames)].iter_mut() {
+                for token in changed.iter() {
+                    if let Some(bucket) = map.get_mut(token) {
+                        *bucket = optimize_bucket(std::mem::take(bucket));
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    /// Shape of the buckets decoded so far.
+    fn stats(&self) -> ListStats {
+        let mut stats = ListStats::default();
+        let mut seen = HashSet::new();
+        let mut buckets: Vec<(Hash, usize)> = Vec::new();
+        for map in [&self.filter_map, &self.hostname_map].iter() {
+            stats.bucket_entries += vec_hashmap_len(map);
+            stats.buckets += map.len();
+            buckets.extend(map.iter().map(|(token, bucket)| (*token, bucket.len())));
+            for filter in map.values().flatten() {
+                if !seen.insert(Arc::as_ptr(filter) as usize) {
+                    continue;
+                }
+                stats.filters += 1;
+                if filter.is_regex() || filter.is_complete_regex() {
+                    stats.regex_filters += 1;
+                }
+                if let Some(regex) = filter.compiled_regex() {
+                    stats.compiled_regexes += 1;
+                    stats.compiled_regex_bytes += regex.to_string().len();
+                }
+            }
+        }
+        buckets.sort_unstable_by(|(token_a, len_a), (token_b, len_b)| len_b.cmp(len_a).then(token_a.cmp(token_b)));
+        buckets.truncate(LARGEST_BUCKETS);
+        stats.largest_buckets = buckets;
+        stats
+    }
+
+    /// Interns filters not shared with other buckets, see `Blocker::compact`.
+    fn intern(&mut self, interner: &mut Interner) {
+        for bucket in self.filter_map.values_mut().chain(self.hostname_map.values_mut()) {
+            for filter in bucket.iter_mut() {
+                if let Some(filter) = Arc::get_mut(filter) {
+                    interner.intern(filter);
+                }
+            }
+        }
+    }
+
+    /// Memory used by the buckets decoded so far.
+    fn memory(&self, counter: &mut MemoryCounter) -> ListMemory {
+        let mut memory = ListMemory::default();
+        for map in [&self.filter_map, &self.hostname_map].iter() {
+            let bytes = map.capacity() * (size_of::<Hash>() + size_of::<Vec<Arc<NetworkFilter>>>());
+            memory.bytes += bytes;
+            memory.unshared_bytes += bytes;
+            for bucket in map.values() {
+                counter.add_bucket(&mut memory, bucket, bucket.capacity());
+            }
+        }
+        memory
     }
 
     pub fn filter_exists(&self, filter: &NetworkFilter) -> Result<bool, BlockerError> {
         // if self.optimized == Some(true) {
         //     return Err(BlockerError::OptimizedFilterExistence)
         // }
+        if filter.is_hostname_only() {
+            if let Some(mut filters) = self.hostname_bucket(&hostname_key(filter)) {
+                if filters.any(|saved_filter| saved_filter.id == filter.id) {
+                    return Ok(true)
+                }
+            }
+        }
+
         let mut tokens: Vec<_> = filter.get_tokens().into_iter().flatten().collect();
 
         if tokens.is_empty() {
@@ -519,7 +1426,7 @@ impl NetworkFilterList {
         }
 
         for token in tokens {
-            if let Some(filters) = self.filter_map.get(&token) {
+            if let Some(filters) = self.bucket(&token) {
                 for saved_filter in filters {
                     if saved_filter.id == filter.id {
                         return Ok(true)
@@ -531,72 +1438,232 @@ impl NetworkFilterList {
         Ok(false)
     }
 
-    pub fn check(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Option<&NetworkFilter> {
-        #[cfg(feature = "metrics")]
-        let mut filters_checked = 0;
-        #[cfg(feature = "metrics")]
-        let mut filter_buckets = 0;
-
-        #[cfg(not(feature = "metrics"))]
-        {
-            if self.filter_map.is_empty() {
-                return None;
-            }
+    /// First filter of the list that matches `request`. The buckets and filters
+    /// visited, as well as any regex that failed to compile, are reported to the
+    /// observer of `progress` if there is one.
+    fn check(&self, list_name: &'static str, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>, disabled_lists: &HashSet<String>, mut progress: CheckProgress) -> Option<&Arc<NetworkFilter>> {
+        let matched = self.find(request, request_tokens, active_tags, disabled_lists, &mut progress);
+
+        if let Some(observer) = progress.observer {
+            observer.on_event(&BlockerEvent::ListChecked {
+                list: list_name,
+                buckets_visited: progress.buckets_visited,
+                filters_evaluated: progress.filters_evaluated,
+                matched: matched.is_some(),
+            });
         }
 
-        if let Some(source_hostname_hashes) = request.source_hostname_hashes.as_ref() {
-            for token in source_hostname_hashes {
-                if let Some(filter_bucket) = self.filter_map.get(token) {
-                    #[cfg(feature = "metrics")]
-                    {
-                        filter_buckets += 1;
-                    }
+        matched
+    }
 
-                    for filter in filter_bucket {
-                        #[cfg(feature = "metrics")]
-                        {
-                            filters_checked += 1;
-                        }
-                        // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                        if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
-                            #[cfg(feature = "metrics")]
-                            print!("true\t{}\t{}\tskipped\t{}\t{}\t", filter_buckets, filters_checked, filter_buckets, filters_checked);
-                            return Some(filter);
-                        }
-                    }
-                }
-            }
+    fn find(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>, disabled_lists: &HashSet<String>, progress: &mut CheckProgress) -> Option<&Arc<NetworkFilter>> {
+        if self.is_empty() {
+            return None;
         }
 
-        #[cfg(feature = "metrics")]
-        print!("false\t{}\t{}\t", filter_buckets, filters_checked);
-        
-        for token in request_tokens {
-            if let Some(filter_bucket) = self.filter_map.get(token) {
-                #[cfg(feature = "metrics")]
-                {
-                    filter_buckets += 1;
-                }
+        for suffix in hostname_suffixes(&request.hostname) {
+            if let Some(filter_bucket) = self.hostname_bucket(&fast_hash(suffix)) {
+                progress.buckets_visited += 1;
                 for filter in filter_bucket {
-                    #[cfg(feature = "metrics")]
-                    {
-                        filters_checked += 1;
-                    }
-                    // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                    if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
-                        #[cfg(feature = "metrics")]
-                        print!("true\t{}\t{}\t", filter_buckets, filters_checked);
+                    if progress.matches(filter, request) && is_enabled(filter, active_tags, disabled_lists) {
                         return Some(filter);
                     }
                 }
             }
         }
 
-        #[cfg(feature = "metrics")]
-        print!("false\t{}\t{}\t", filter_buckets, filters_checked);
+        let source_tokens = request.source_hostname_hashes.as_deref().unwrap_or_default();
+        for token in source_tokens.iter().chain(request_tokens.iter()) {
+            if let Some(filter) = self.check_bucket(token, request, active_tags, disabled_lists, progress) {
+                return Some(filter);
+            }
+        }
 
         None
     }
+
+    /// First filter stored under `token` that matches `request`.
+    #[cfg(not(feature = "multi-pattern-matching"))]
+    fn check_bucket(&self, token: &Hash, request: &Request, active_tags: &HashSet<String>, disabled_lists: &HashSet<String>, progress: &mut CheckProgress) -> Option<&Arc<NetworkFilter>> {
+        let mut filter_bucket = self.bucket(token)?;
+        progress.buckets_visited += 1;
+        filter_bucket.find(|filter| progress.matches(filter, request) && is_enabled(filter, active_tags, disabled_lists))
+    }
+
+    /// First filter stored under `token` that matches `request`. The plain
+    /// patterns of the bucket are all looked for in a single scan of the URL.
+    #[cfg(feature = "multi-pattern-matching")]
+    fn check_bucket(&self, token: &Hash, request: &Request, active_tags: &HashSet<String>, disabled_lists: &HashSet<String>, progress: &mut CheckProgress) -> Option<&Arc<NetworkFilter>> {
+        let mut filter_bucket = self.bucket(token)?;
+        progress.buckets_visited += 1;
+        let plain_patterns = self.plain_patterns
+            .borrow_mut()
+            .entry(*token)
+            .or_insert_with(|| PlainPatterns::new(self.bucket(token)?.map(|filter| filter.as_ref())).map(std::rc::Rc::new))
+            .clone();
+        let plain_patterns = match plain_patterns {
+            Some(plain_patterns) => plain_patterns,
+            None => return filter_bucket
+                .find(|filter| progress.matches(filter, request) && is_enabled(filter, active_tags, disabled_lists)),
+        };
+
+        let found = plain_patterns.find(&request.url);
+        filter_bucket
+            .enumerate()
+            .find(|(i, filter)| {
+                let matches = match plain_patterns.pattern_of(*i) {
+                    // Filters whose pattern is not in the URL are not evaluated at all
+                    Some(pattern) => found[pattern] && progress.matches_options(filter, request),
+                    None => progress.matches(filter, request),
+                };
+                matches && is_enabled(filter, active_tags, disabled_lists)
+            })
+            .map(|(_, filter)| filter)
+    }
+}
+
+/// Buckets and filters visited while checking a single list, how to compile
+/// regexes and where to report those that fail to compile.
+struct CheckProgress<'a> {
+    regex_limits: &'a RegexLimits,
+    observer: Option<&'a dyn BlockerObserver>,
+    buckets_visited: usize,
+    filters_evaluated: usize,
+}
+
+impl<'a> CheckProgress<'a> {
+    fn new(regex_limits: &'a RegexLimits, observer: Option<&'a dyn BlockerObserver>) -> CheckProgress<'a> {
+        CheckProgress {
+            regex_limits,
+            observer,
+            buckets_visited: 0,
+            filters_evaluated: 0,
+        }
+    }
+
+    fn matches(&mut self, filter: &NetworkFilter, request: &Request) -> bool {
+        self.filters_evaluated += 1;
+        if filter.is_complete_regex() {
+            // Compiled here, before `matches` would compile it with the default limits
+            filter.get_regex_with_limits(self.regex_limits);
+        }
+        let matches = filter.matches(request);
+        if let Some(observer) = self.observer {
+            if !matches && (filter.is_regex() || filter.is_complete_regex()) {
+                if let CompiledRegex::RegexParsingError(e) = &*filter.get_regex() {
+                    observer.on_event(&BlockerEvent::RegexCompileFailed {
+                        filter: filter.to_string(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+        matches
+    }
+
+    #[cfg(feature = "multi-pattern-matching")]
+    fn matches_options(&mut self, filter: &NetworkFilter, request: &Request) -> bool {
+        self.filters_evaluated += 1;
+        filter.matches_options(request)
+    }
+}
+
+/// Whether a filter is tagged with an active tag, or not tagged at all, and does not come from a disabled list.
+fn is_enabled(filter: &NetworkFilter, active_tags: &HashSet<String>, disabled_lists: &HashSet<String>) -> bool {
+    filter.tag.as_deref().map(|t| active_tags.contains(t)).unwrap_or(true)
+        && (disabled_lists.is_empty() || filter.list_id.as_deref().map(|l| !disabled_lists.contains(l)).unwrap_or(true))
+}
+
+/// Filters stored under `token` in `map` or `lazy`, or `None` if there is no such bucket.
+fn lookup<'a>(map: &'a HashMap<Hash, Vec<Arc<NetworkFilter>>>, lazy: Option<&'a LazyBuckets>, token: &Hash) -> Option<impl Iterator<Item = &'a Arc<NetworkFilter>>> {
+    let loaded = map.get(token);
+    let lazy = lazy.and_then(|lazy| lazy.get(*token));
+    if loaded.is_none() && lazy.is_none() {
+        return None;
+    }
+    Some(loaded.into_iter().flatten().chain(lazy.into_iter().flatten()))
+}
+
+/// Buckets of `map`, merged with any lazily loaded ones.
+fn merge_lazy<'a>(map: &'a HashMap<Hash, Vec<Arc<NetworkFilter>>>, lazy: Option<&LazyBuckets>) -> std::borrow::Cow<'a, HashMap<Hash, Vec<Arc<NetworkFilter>>>> {
+    match lazy {
+        None => std::borrow::Cow::Borrowed(map),
+        Some(lazy) => {
+            let mut buckets = map.clone();
+            for (token, filters) in lazy.iter() {
+                buckets.entry(token).or_insert_with(Vec::new).extend(filters.iter().cloned());
+            }
+            std::borrow::Cow::Owned(buckets)
+        }
+    }
+}
+
+fn optimize_buckets(filter_map: HashMap<Hash, Vec<Arc<NetworkFilter>>>) -> HashMap<Hash, Vec<Arc<NetworkFilter>>> {
+    let mut optimized_map = HashMap::with_capacity(filter_map.len());
+    for (key, filters) in filter_map {
+        optimized_map.insert(key, optimize_bucket(filters));
+    }
+
+    // won't mutate anymore, shrink to fit items
+    optimized_map.shrink_to_fit();
+    optimized_map
+}
+
+/// Fuses the filters of a bucket, leaving out those also stored in other buckets.
+fn optimize_bucket(filters: Vec<Arc<NetworkFilter>>) -> Vec<Arc<NetworkFilter>> {
+    let mut unoptimized: Vec<NetworkFilter> = Vec::with_capacity(filters.len());
+    let mut unoptimizable: Vec<Arc<NetworkFilter>> = Vec::with_capacity(filters.len());
+    for f in filters {
+        match Arc::try_unwrap(f) {
+            Ok(f) => unoptimized.push(f),
+            Err(af) => unoptimizable.push(af)
+        }
+    }
+
+    let mut optimized: Vec<_> = if unoptimized.len() > 1 {
+        optimizer::optimize(unoptimized).into_iter().map(Arc::new).collect()
+    } else {
+        // nothing to optimize
+        unoptimized.into_iter().map(Arc::new).collect()
+    };
+
+    optimized.append(&mut unoptimizable);
+    optimized
+}
+
+/**
+ * Whether `filter` may have been fused by optimizations from several filters,
+ * including the one `badfilter` disables: either its patterns or its
+ * `$domain=` option combine those of the filter and others.
+ */
+fn may_fuse_badfiltered(filter: &NetworkFilter, badfilter: &NetworkFilter) -> bool {
+    let ignored = NetworkFilterMask::IS_REGEX | NetworkFilterMask::IS_COMPLETE_REGEX | NetworkFilterMask::BAD_FILTER;
+    let (has_domains, more_domains) = match (filter.opt_domains.as_deref(), badfilter.opt_domains.as_deref()) {
+        (Some(domains), Some(badfilter_domains)) => {
+            let has_domains = badfilter_domains.iter().all(|domain| domains.binary_search(domain).is_ok());
+            (has_domains, has_domains && domains.len() > badfilter_domains.len())
+        }
+        (domains, badfilter_domains) => (domains == badfilter_domains, false),
+    };
+    let fused = match (&filter.filter, &badfilter.filter) {
+        (FilterPart::AnyOf(patterns), FilterPart::Simple(pattern)) => patterns.contains(pattern) && has_domains,
+        (filter_part, badfilter_part) => filter_part.string_view() == badfilter_part.string_view() && more_domains,
+    };
+    fused
+        && (filter.mask & !ignored) == (badfilter.mask & !ignored)
+        && filter.hostname == badfilter.hostname
+        && filter.opt_not_domains == badfilter.opt_not_domains
+        && filter.csp == badfilter.csp
+}
+
+/// Key of a `||hostname^` filter in `NetworkFilterList::hostname_map`.
+fn hostname_key(filter: &NetworkFilter) -> Hash {
+    fast_hash(filter.hostname.as_deref().unwrap_or_default())
+}
+
+/// `hostname` followed by each of its parent domains, e.g. `a.b.com`, `b.com` and `com`.
+fn hostname_suffixes(hostname: &str) -> impl Iterator<Item = &str> {
+    std::iter::once(hostname).chain(hostname.match_indices('.').map(move |(i, _)| &hostname[i + 1..]))
 }
 
 fn insert_dup<K, V, H: std::hash::BuildHasher>(map: &mut HashMap<K, Vec<V>, H>, k: K, v: V)
@@ -614,6 +1681,32 @@ fn vec_hashmap_len<K: std::cmp::Eq + std::hash::Hash, V, H: std::hash::BuildHash
     size
 }
 
+/**
+ * Picks the token to store a filter under among `tokens`: the one found in the
+ * fewest profiled requests, if there is a `profile`, then the one with the fewest
+ * other filters, as counted by `filters_with` (`None` when there are none). Falls
+ * back to the 0 token, checked for every request, when there are no tokens.
+ */
+fn best_token<F: Fn(&Hash) -> Option<usize>>(tokens: &[Hash], filters_with: F, profile: Option<&TokenProfile>) -> Hash {
+    let mut best_token: Hash = 0;
+    let mut min_cost = (u32::MAX, usize::MAX);
+    for token in tokens {
+        let requests = profile.map(|profile| profile.frequency(*token)).unwrap_or(0);
+        match filters_with(token) {
+            None if requests <= min_cost.0 => {
+                min_cost = (requests, 0);
+                best_token = *token
+            }
+            Some(count) if (requests, count) < min_cost => {
+                min_cost = (requests, count);
+                best_token = *token
+            }
+            _ => {}
+        }
+    }
+    best_token
+}
+
 fn token_histogram<T>(filter_tokens: &[(T, Vec<Vec<Hash>>)]) -> (u32, HashMap<Hash, u32>) {
     let mut tokens_histogram: HashMap<Hash, u32> = HashMap::new();
     let mut number_of_tokens = 0;
@@ -816,6 +1909,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn network_filter_list_uses_token_profile() {
+        let filters = || vec![NetworkFilter::parse("/banner/ads/pixel", true).unwrap()];
+        let filter_list = NetworkFilterList::new(filters(), false);
+        assert!(filter_list.filter_map.get(&fast_hash("banner")).is_some());
+
+        // `banner` shows up in more profiled requests than `ads`
+        let profile = TokenProfile::from_urls([
+            "https://example.com/banner/1.png",
+            "https://example.com/banner/2.png",
+            "https://example.com/ads/1.png",
+        ]);
+        let mut filter_list = NetworkFilterList::new_with_token_profile(filters(), false, Some(&profile));
+        assert!(filter_list.filter_map.get(&fast_hash("banner")).is_none());
+        assert!(filter_list.filter_map.get(&fast_hash("ads")).is_some());
+
+        filter_list.filter_add(NetworkFilter::parse("/banner/track/pixel", true).unwrap(), Some(&profile));
+        assert!(filter_list.filter_map.get(&fast_hash("banner")).is_none());
+        assert!(filter_list.filter_map.get(&fast_hash("track")).is_some());
+    }
+
     fn test_requests_filters(filters: &Vec<&str>, requests: &Vec<(Request, bool)>) {
         let network_filters: Vec<_> = filters
             .into_iter()
@@ -827,7 +1941,7 @@ mod tests {
         requests.into_iter().for_each(|(req, expected_result)| {
             let mut tokens = Vec::new();
             req.get_tokens(&mut tokens);
-            let matched_rule = filter_list.check(&req, &tokens, &HashSet::new());
+            let matched_rule = filter_list.check("filters", &req, &tokens, &HashSet::new(), &HashSet::new(), CheckProgress::new(&RegexLimits::default(), None));
             if *expected_result {
                 assert!(matched_rule.is_some(), "Expected match for {}", req.url);
             } else {
@@ -1315,12 +2429,394 @@ mod blocker_tests {
             }
         });
     }
+
+    #[test]
+    fn observer_receives_events() {
+        use crate::observer::MemoryObserver;
+
+        let filters = vec![
+            String::from("||foo.com^$redirect=noopjs"),
+            String::from("/(broken/"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: false,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let observer = Arc::new(MemoryObserver::default());
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        blocker.with_observer(observer.clone());
+
+        let matched_rule = blocker.check(&Request::from_url("https://foo.com/script.js").unwrap());
+        assert!(matched_rule.matched);
+        let events = observer.events();
+        assert!(events.contains(&BlockerEvent::RedirectResourceMissing { redirect: String::from("noopjs") }));
+        assert!(events.contains(&BlockerEvent::ListChecked { list: "redirects", buckets_visited: 1, filters_evaluated: 1, matched: true }));
+        assert!(events.contains(&BlockerEvent::ListChecked { list: "exceptions", buckets_visited: 0, filters_evaluated: 0, matched: false }));
+
+        observer.clear();
+        let matched_rule = blocker.check(&Request::from_url("https://bar.com/script.js").unwrap());
+        assert!(!matched_rule.matched);
+        let events = observer.events();
+        assert!(events.iter().any(|e| matches!(e, BlockerEvent::RegexCompileFailed { .. })));
+        assert!(events.contains(&BlockerEvent::ListChecked { list: "filters", buckets_visited: 1, filters_evaluated: 1, matched: false }));
+    }
+
+    #[test]
+    fn warm_up_regexes_reports_failures() {
+        use crate::observer::MemoryObserver;
+
+        let filters = vec![
+            String::from("/(broken/"),
+            String::from("@@/(also-broken/"),
+            String::from("/ads*/banner^"),
+            String::from("||foo.com^"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: true,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let observer = Arc::new(MemoryObserver::default());
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        blocker.with_observer(observer.clone());
+
+        let mut failed = blocker.warm_up_regexes();
+        failed.sort();
+        assert_eq!(failed, vec![String::from("/(broken/"), String::from("@@/(also-broken/")]);
+        assert_eq!(observer.events().iter().filter(|e| matches!(e, BlockerEvent::RegexCompileFailed { .. })).count(), 2);
+    }
+
+    #[test]
+    fn regex_limits_apply_to_a_single_blocker() {
+        let filters = vec![
+            String::from(r"/ads\d+\/banner[a-z]{2,}/"),
+        ];
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: true,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+        let request = Request::from_url("https://example.com/ads12/bannerad.gif").unwrap();
+
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+        let mut limited = Blocker::new(network_filters, &blocker_options);
+        limited.with_regex_limits(RegexLimits { size_limit: 10, dfa_size_limit: 10 });
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+        let unlimited = Blocker::new(network_filters, &blocker_options);
+
+        assert!(!limited.check(&request).matched);
+        assert!(unlimited.check(&request).matched);
+        assert_eq!(limited.warm_up_regexes(), filters);
+        assert!(unlimited.warm_up_regexes().is_empty());
+    }
+
+    #[test]
+    fn hostname_filters_are_indexed() {
+        let filters = vec![
+            String::from("||foo.com^"),
+            String::from("||ads.bar.com^$script"),
+            String::from("||baz.com/ads^"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: true,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        assert_eq!(vec_hashmap_len(&blocker.filters.hostname_map), 2);
+        assert_eq!(vec_hashmap_len(&blocker.filters.filter_map), 1);
+
+        let check = |blocker: &Blocker, url: &str, request_type: &str| {
+            blocker.check(&Request::from_urls(url, "https://example.com", request_type).unwrap()).matched
+        };
+        assert!(check(&blocker, "https://foo.com/script.js", "script"));
+        assert!(check(&blocker, "https://sub.foo.com/script.js", "script"));
+        assert!(!check(&blocker, "https://notfoo.com/script.js", "script"));
+        assert!(!check(&blocker, "https://foo.com.example.org/script.js", "script"));
+        assert!(check(&blocker, "https://cdn.ads.bar.com/script.js", "script"));
+        assert!(!check(&blocker, "https://cdn.ads.bar.com/image.png", "image"));
+        assert!(!check(&blocker, "https://bar.com/script.js", "script"));
+        assert!(check(&blocker, "https://baz.com/ads/script.js", "script"));
+
+        let filter = NetworkFilter::parse("||qux.com^", true).unwrap();
+        blocker.filter_add(filter.clone()).unwrap();
+        assert_eq!(vec_hashmap_len(&blocker.filters.hostname_map), 3);
+        assert_eq!(blocker.filter_exists(&filter), Ok(true));
+        assert!(check(&blocker, "https://www.qux.com/script.js", "script"));
+    }
+
+    #[test]
+    #[cfg(feature = "multi-pattern-matching")]
+    fn plain_patterns_keep_options_and_order() {
+        let filters = vec![
+            String::from("/ads/banner$script"),
+            String::from("/ads/banner$image,third-party"),
+            String::from("/ads/popup"),
+            String::from("/ads/track$~third-party"),
+            String::from("/ads/*/pixel"),
+            String::from("/ads/frame$subdocument"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: true,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        assert_eq!(blocker.filters.filter_map.get(&fast_hash("ads")).map(|bucket| bucket.len()), Some(6));
+
+        let check = |blocker: &Blocker, url: &str, source: &str, request_type: &str| {
+            blocker.check(&Request::from_urls(url, source, request_type).unwrap()).filter
+        };
+        assert_eq!(check(&blocker, "https://cdn.com/ads/banner.js", "https://example.com", "script"), Some(String::from("/ads/banner$script")));
+        assert_eq!(check(&blocker, "https://cdn.com/ads/banner.png", "https://example.com", "image"), Some(String::from("/ads/banner$image,third-party")));
+        assert_eq!(check(&blocker, "https://cdn.com/ads/banner.png", "https://cdn.com", "image"), None);
+        assert_eq!(check(&blocker, "https://cdn.com/ads/popup.html", "https://example.org", "image"), Some(String::from("/ads/popup")));
+        assert_eq!(check(&blocker, "https://cdn.com/ads/track.gif", "https://example.org", "image"), None);
+        assert_eq!(check(&blocker, "https://cdn.com/ads/1/pixel.gif", "https://example.org", "image"), Some(String::from("/ads/*/pixel")));
+        assert_eq!(check(&blocker, "https://cdn.com/ads/frame.html", "https://example.org", "image"), None);
+
+        blocker.filter_add(NetworkFilter::parse("/ads/banner", true).unwrap()).unwrap();
+        assert_eq!(check(&blocker, "https://cdn.com/ads/banner.png", "https://cdn.com", "image"), Some(String::from("/ads/banner")));
+    }
+
+    #[test]
+    #[cfg(feature = "multi-pattern-matching")]
+    fn observed_checks_use_plain_patterns() {
+        use crate::observer::MemoryObserver;
+
+        let filters = vec![
+            String::from("/ads/banner$script"),
+            String::from("/ads/popup"),
+            String::from("/ads/track"),
+            String::from("/ads/frame"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: true,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let observer = Arc::new(MemoryObserver::default());
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        blocker.with_observer(observer.clone());
+        assert_eq!(blocker.filters.filter_map.get(&fast_hash("ads")).map(|bucket| bucket.len()), Some(4));
+
+        let matched_rule = blocker.check(&Request::from_urls("https://cdn.com/ads/frame.html", "https://example.com", "image").unwrap());
+        assert_eq!(matched_rule.filter, Some(String::from("/ads/frame")));
+        // Only the filter whose pattern is in the URL gets evaluated
+        assert!(observer.events().contains(&BlockerEvent::ListChecked { list: "filters", buckets_visited: 1, filters_evaluated: 1, matched: true }));
+    }
+
+    #[test]
+    fn compact_shares_data_between_lists() {
+        let filters = vec![
+            String::from("||ads.example.com^$redirect=noopjs,domain=a.com|b.com"),
+            String::from("||ads.example.com/banner$important,domain=a.com|b.com"),
+            String::from("@@||ads.example.com/ok$domain=a.com|b.com"),
+            String::from("/pixel$tag=stuff,domain=a.com|b.com"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: true,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        let report = blocker.memory_report();
+        assert_eq!(report.redirects.filters, 1);
+        assert_eq!(report.exceptions.filters, 1);
+        assert_eq!(report.filters_tagged.filters, 1);
+        assert_eq!(report.total().filters, 4);
+        // the first list keeps the hostname and domains, the others refer to it
+        assert_eq!(report.exceptions.bytes, report.exceptions.unshared_bytes);
+        assert!(report.importants.bytes < report.importants.unshared_bytes);
+        assert!(report.redirects.bytes < report.redirects.unshared_bytes);
+        assert!(report.filters_tagged.bytes < report.filters_tagged.unshared_bytes);
+
+        // capacities of decoded maps and buckets differ, what is saved does not
+        let saved = |report: MemoryReport| report.total().unshared_bytes - report.total().bytes;
+        let mut deserialized = crate::serialization::deserialize(&crate::serialization::serialize(&blocker).unwrap()).unwrap();
+        assert_eq!(saved(deserialized.memory_report()), saved(blocker.memory_report()));
+
+        blocker.filter_add(NetworkFilter::parse("||ads.example.com/other$domain=a.com|b.com", true).unwrap()).unwrap();
+        deserialized.filter_add(NetworkFilter::parse("||ads.example.com/other$domain=a.com|b.com", true).unwrap()).unwrap();
+        let added = blocker.memory_report().total();
+        assert_eq!(added.filters, 5);
+        blocker.compact();
+        let compacted = blocker.memory_report().total();
+        assert_eq!(compacted.unshared_bytes, added.unshared_bytes);
+        assert!(compacted.bytes < added.bytes);
+        deserialized.compact();
+        assert_eq!(saved(deserialized.memory_report()), compacted.unshared_bytes - compacted.bytes);
+    }
+
+    #[test]
+    fn hot_filters_are_promoted_and_evicted() {
+        let filters = vec![
+            String::from("/banner-ad."),
+            String::from("/popup-ad."),
+            String::from("/track-ad.$bug=123"),
+            String::from("@@/banner-ad.$domain=example.org"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: true,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        assert_eq!(blocker.hot_filter_options(), HotFilterOptions::default());
+        blocker.with_hot_filters(HotFilterOptions { capacity: 1, promote_after: 2 });
+
+        let check = |blocker: &Blocker, url: &str, source: &str| {
+            blocker.check(&Request::from_urls(url, source, "image").unwrap())
+        };
+        let promoted = |blocker: &Blocker| -> Vec<String> {
+            blocker.hot_filters.borrow().promoted.iter().map(|(_, f)| f.to_string()).collect()
+        };
+
+        check(&blocker, "https://cdn.com/banner-ad.png", "https://example.com");
+        assert!(promoted(&blocker).is_empty());
+        check(&blocker, "https://cdn.com/banner-ad.png", "https://example.com");
+        assert_eq!(promoted(&blocker), vec!["/banner-ad."]);
+
+        // Exceptions still apply to hot filters
+        let result = check(&blocker, "https://cdn.com/banner-ad.png", "https://example.org");
+        assert!(!result.matched);
+        assert_eq!(result.filter, Some(String::from("/banner-ad.")));
+        assert_eq!(result.exception, Some(String::from("@@/banner-ad.$domain=example.org")));
+
+        // Filters with a bug ID are never promoted
+        for _ in 0..4 {
+            check(&blocker, "https://cdn.com/track-ad.png", "https://example.com");
+        }
+        assert_eq!(promoted(&blocker), vec!["/banner-ad."]);
+
+        // Counts decay, so a filter which matches more often takes over
+        for _ in 0..3 {
+            check(&blocker, "https://cdn.com/popup-ad.png", "https://example.com");
+        }
+        assert_eq!(promoted(&blocker), vec!["/popup-ad."]);
+        assert!(check(&blocker, "https://cdn.com/popup-ad.png", "https://example.com").matched);
+        assert!(!check(&blocker, "https://cdn.com/other.png", "https://example.com").matched);
+
+        blocker.with_hot_filters(HotFilterOptions { capacity: 0, promote_after: 1 });
+        for _ in 0..3 {
+            assert!(check(&blocker, "https://cdn.com/popup-ad.png", "https://example.com").matched);
+        }
+        assert!(promoted(&blocker).is_empty());
+    }
+
+    #[test]
+    fn result_cache_returns_earlier_results() {
+        let filters = vec![
+            String::from("/banner-ad."),
+            String::from("@@/banner-ad.$domain=example.org"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: true,
+            enable_optimizations: false,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        assert_eq!(blocker.result_cache_capacity(), 0);
+        blocker.with_result_cache(2);
+        assert_eq!(blocker.result_cache_capacity(), 2);
+        let cached = |blocker: &Blocker| blocker.result_cache.borrow().as_ref().map(|cache| cache.len()).unwrap();
+
+        let check = |blocker: &Blocker, url: &str, source: &str, request_type: &str| {
+            blocker.check(&Request::from_urls(url, source, request_type).unwrap()).matched
+        };
+        assert!(check(&blocker, "https://cdn.com/banner-ad.png", "https://example.com", "image"));
+        assert!(check(&blocker, "https://cdn.com/banner-ad.png", "https://example.com", "image"));
+        assert_eq!(cached(&blocker), 1);
+
+        // Different source or type are different entries
+        assert!(!check(&blocker, "https://cdn.com/banner-ad.png", "https://example.org", "image"));
+        assert!(check(&blocker, "https://cdn.com/banner-ad.png", "https://example.com", "script"));
+        assert_eq!(cached(&blocker), 2);
+
+        blocker.filter_add(NetworkFilter::parse("/other-ad.", true).unwrap()).unwrap();
+        assert_eq!(cached(&blocker), 0);
+
+        blocker.with_result_cache(0);
+        assert!(check(&blocker, "https://cdn.com/other-ad.png", "https://example.com", "image"));
+        assert!(blocker.result_cache.borrow().is_none());
+    }
+
+    #[test]
+    fn is_hostname_blocked_works() {
+        let filters = vec![
+            String::from("||foo.com^"),
+            String::from("||bar.com^$script"),
+            String::from("||baz.com^$domain=example.com"),
+            String::from("||qux.com/ads^"),
+            String::from("||allowed.foo.com^"),
+            String::from("@@||allowed.foo.com^"),
+            String::from("||important.com^$important"),
+            String::from("@@||important.com^"),
+            String::from("@@||foo.com^$script"),
+            String::from("@@||foo.com^$domain=example.com"),
+            String::from("@@||sub.foo.com^$~third-party"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, false, true);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            debug: false,
+            enable_optimizations: true,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+
+        let blocker = Blocker::new(network_filters, &blocker_options);
+        assert!(blocker.is_hostname_blocked("foo.com"));
+        assert!(blocker.is_hostname_blocked("sub.FOO.com"));
+        // Exceptions restricted to some requests do not unblock the whole hostname
+        assert!(blocker.is_hostname_blocked("www.sub.foo.com"));
+        assert!(!blocker.is_hostname_blocked("notfoo.com"));
+        assert!(!blocker.is_hostname_blocked("bar.com"));
+        assert!(!blocker.is_hostname_blocked("baz.com"));
+        assert!(!blocker.is_hostname_blocked("qux.com"));
+        assert!(!blocker.is_hostname_blocked("allowed.foo.com"));
+        assert!(!blocker.is_hostname_blocked("sub.allowed.foo.com"));
+        assert!(blocker.is_hostname_blocked("important.com"));
+    }
 }
 
 mod legacy_rule_parsing_tests {
     use crate::utils::rules_from_lists;
     use crate::lists::parse_filters;
-    use crate::blocker::{Blocker, BlockerOptions};
+    use crate::blocker::{Blocker, BlockerOptions, NetworkFilterList};
     use crate::blocker::vec_hashmap_len;
 
     struct ListCounts {
@@ -1379,13 +2875,15 @@ mod legacy_rule_parsing_tests {
 
         let blocker = Blocker::new(network_filters, &blocker_options);
 
+        let list_len = |list: &NetworkFilterList| vec_hashmap_len(&list.filter_map) + vec_hashmap_len(&list.hostname_map);
+
         // Some filters in the filter_map are pointed at by multiple tokens, increasing the total number of items
-        assert!(vec_hashmap_len(&blocker.exceptions.filter_map) >= expectation.exceptions, "Number of collected exceptions does not match expectation");
+        assert!(list_len(&blocker.exceptions) >= expectation.exceptions, "Number of collected exceptions does not match expectation");
 
-        assert!(vec_hashmap_len(&blocker.filters.filter_map) + 
-            vec_hashmap_len(&blocker.importants.filter_map) +
-            vec_hashmap_len(&blocker.redirects.filter_map) +
-            vec_hashmap_len(&blocker.csp.filter_map) >=
+        assert!(list_len(&blocker.filters) +
+            list_len(&blocker.importants) +
+            list_len(&blocker.redirects) +
+            list_len(&blocker.csp) >=
             expectation.filters, "Number of collected network filters does not match expectation");
     }
 
@@ -0,0 +1,216 @@
+//! Sharing of equal filter data between filters, and accounting of the memory they use.
+
+use hashbrown::HashSet;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use crate::filters::network::{FilterPart, NetworkFilter};
+use crate::utils::Hash;
+
+/// Reference counts stored in front of the data of every `Arc`/`Rc` allocation.
+const REFCOUNTS: usize = 2 * size_of::<usize>();
+
+/**
+ * Makes filters point to one shared copy of each distinct hostname, redirect,
+ * csp, tag and domain list. Lists repeat the same few redirect resources, tags
+ * and `$domain=` sets over and over, so this saves a copy for all but the first
+ * filter using them.
+ */
+#[derive(Default)]
+pub struct Interner {
+    strings: HashSet<Arc<str>>,
+    domains: HashSet<Arc<[Hash]>>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    pub fn intern(&mut self, filter: &mut NetworkFilter) {
+        self.intern_str(&mut filter.hostname);
+        self.intern_str(&mut filter.redirect);
+        self.intern_str(&mut filter.csp);
+        self.intern_str(&mut filter.tag);
+        self.intern_str(&mut filter.opt_domains_text);
+        self.intern_str(&mut filter.list_id);
+        self.intern_domains(&mut filter.opt_domains);
+        self.intern_domains(&mut filter.opt_not_domains);
+    }
+
+    fn intern_str(&mut self, value: &mut Option<Arc<str>>) {
+        if let Some(value) = value.as_mut() {
+            match self.strings.get(&**value) {
+                Some(interned) => *value = Arc::clone(interned),
+                None => {
+                    self.strings.insert(Arc::clone(value));
+                }
+            }
+        }
+    }
+
+    fn intern_domains(&mut self, value: &mut Option<Arc<[Hash]>>) {
+        if let Some(value) = value.as_mut() {
+            match self.domains.get(&**value) {
+                Some(interned) => *value = Arc::clone(interned),
+                None => {
+                    self.domains.insert(Arc::clone(value));
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Approximate heap memory used by the filters of one list, in bytes. `bytes`
+ * counts data shared with filters of other lists only once, for the list it
+ * was first seen in; `unshared_bytes` is what the same filters would use if
+ * each of them had its own copy, as they did before being interned. Compiled
+ * regexes are not included.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ListMemory {
+    pub filters: usize,
+    pub bytes: usize,
+    pub unshared_bytes: usize,
+}
+
+impl ListMemory {
+    fn add(&mut self, other: &ListMemory) {
+        self.filters += other.filters;
+        self.bytes += other.bytes;
+        self.unshared_bytes += other.unshared_bytes;
+    }
+}
+
+/// Memory used by each list of a `Blocker`, see `Blocker::memory_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub csp: ListMemory,
+    pub exceptions: ListMemory,
+    pub importants: ListMemory,
+    pub redirects: ListMemory,
+    pub filters_tagged: ListMemory,
+    pub filters: ListMemory,
+}
+
+impl MemoryReport {
+    pub fn total(&self) -> ListMemory {
+        let mut total = ListMemory::default();
+        for list in [&self.csp, &self.exceptions, &self.importants, &self.redirects, &self.filters_tagged, &self.filters].iter() {
+            total.add(list);
+        }
+        total
+    }
+}
+
+/// Adds up `ListMemory` of lists, remembering which allocations were already counted.
+#[derive(Default)]
+pub(crate) struct MemoryCounter {
+    seen: HashSet<usize>,
+}
+
+impl MemoryCounter {
+    /// Counts a bucket or other vector of filters, along with the filters not counted yet.
+    pub fn add_bucket(&mut self, memory: &mut ListMemory, bucket: &[Arc<NetworkFilter>], capacity: usize) {
+        let bytes = capacity * size_of::<Arc<NetworkFilter>>();
+        memory.bytes += bytes;
+        memory.unshared_bytes += bytes;
+        for filter in bucket {
+            if self.first_seen(Arc::as_ptr(filter) as *const u8) {
+                let bytes = REFCOUNTS + size_of::<NetworkFilter>();
+                memory.bytes += bytes;
+                memory.unshared_bytes += bytes;
+                self.add_filter(memory, filter);
+            }
+        }
+    }
+
+    /// Counts the heap data of a filter, not including the filter itself.
+    pub fn add_filter(&mut self, memory: &mut ListMemory, filter: &NetworkFilter) {
+        memory.filters += 1;
+        let owned = filter_part_bytes(&filter.filter)
+            + filter.raw_line.as_ref().map(|line| line.capacity()).unwrap_or(0)
+            + filter.fuzzy_signature.as_ref().map(|signature| signature.capacity() * size_of::<Hash>()).unwrap_or(0)
+            // lazily compiled regex slot
+            + REFCOUNTS + size_of::<std::cell::RefCell<Option<std::rc::Rc<()>>>>();
+        memory.bytes += owned;
+        memory.unshared_bytes += owned;
+
+        for value in [&filter.hostname, &filter.redirect, &filter.csp, &filter.tag, &filter.opt_domains_text, &filter.list_id].iter().copied().flatten() {
+            self.add_shared(memory, value.as_ptr(), REFCOUNTS + value.len());
+        }
+        for value in [&filter.opt_domains, &filter.opt_not_domains].iter().copied().flatten() {
+            self.add_shared(memory, value.as_ptr() as *const u8, REFCOUNTS + value.len() * size_of::<Hash>());
+        }
+    }
+
+    fn add_shared(&mut self, memory: &mut ListMemory, ptr: *const u8, bytes: usize) {
+        if self.first_seen(ptr) {
+            memory.bytes += bytes;
+        }
+        memory.unshared_bytes += bytes;
+    }
+
+    fn first_seen(&mut self, ptr: *const u8) -> bool {
+        self.seen.insert(ptr as usize)
+    }
+}
+
+fn filter_part_bytes(part: &FilterPart) -> usize {
+    match part {
+        FilterPart::Empty => 0,
+        FilterPart::Simple(pattern) => pattern.capacity(),
+        FilterPart::AnyOf(patterns) => {
+            patterns.capacity() * size_of::<String>() + patterns.iter().map(|pattern| pattern.capacity()).sum::<usize>()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_equal_values() {
+        let mut interner = Interner::new();
+        let mut first = NetworkFilter::parse("||foo.com^$domain=a.com|b.com,redirect=noop.js", true).unwrap();
+        let mut second = NetworkFilter::parse("||foo.com/ads$domain=a.com|b.com,redirect=noop.js", true).unwrap();
+        let mut third = NetworkFilter::parse("||bar.com^$domain=a.com,redirect=noop.js", true).unwrap();
+        interner.intern(&mut first);
+        interner.intern(&mut second);
+        interner.intern(&mut third);
+
+        let same = |a: &Option<Arc<str>>, b: &Option<Arc<str>>| Arc::ptr_eq(a.as_ref().unwrap(), b.as_ref().unwrap());
+        assert!(same(&first.hostname, &second.hostname));
+        assert!(same(&first.redirect, &third.redirect));
+        assert!(!same(&first.hostname, &third.hostname));
+        assert!(Arc::ptr_eq(first.opt_domains.as_ref().unwrap(), second.opt_domains.as_ref().unwrap()));
+        assert!(!Arc::ptr_eq(first.opt_domains.as_ref().unwrap(), third.opt_domains.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn counts_shared_data_once() {
+        let mut filters: Vec<_> = ["||foo.com^$redirect=noop.js", "||foo.com/ads$redirect=noop.js"]
+            .iter()
+            .map(|rule| NetworkFilter::parse(rule, true).unwrap())
+            .collect();
+
+        let mut unshared = ListMemory::default();
+        for filter in filters.iter() {
+            MemoryCounter::default().add_filter(&mut unshared, filter);
+        }
+        assert_eq!(unshared.bytes, unshared.unshared_bytes);
+
+        let mut interner = Interner::new();
+        filters.iter_mut().for_each(|filter| interner.intern(filter));
+        let mut counter = MemoryCounter::default();
+        let mut shared = ListMemory::default();
+        for filter in filters.iter() {
+            counter.add_filter(&mut shared, filter);
+        }
+        assert_eq!(shared.filters, 2);
+        assert_eq!(shared.unshared_bytes, unshared.unshared_bytes);
+        assert_eq!(shared.bytes + 2 * REFCOUNTS + "foo.com".len() + "noop.js".len(), shared.unshared_bytes);
+    }
+}
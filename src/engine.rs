@@ -1,13 +1,15 @@
 
-use crate::blocker::{Blocker, BlockerError, BlockerOptions, BlockerResult};
-use crate::lists::parse_filters;
+use crate::blocker::{Blocker, BlockerError, BlockerOptions, BlockerResult, BlockerStats, HotFilterOptions};
+use crate::lists::{parse_filters, parse_filters_for_list, NetworkFilterReader};
 use crate::request::Request;
-use crate::filters::network::NetworkFilter;
+use crate::filters::network::{NetworkFilter, RegexLimits};
 use crate::resources::{Resources, Resource};
-use rmps;
-use flate2::write::GzEncoder;
-use flate2::read::GzDecoder;
-use flate2::Compression;
+use crate::observer::BlockerObserver;
+use crate::serialization;
+use crate::token_profile::TokenProfile;
+use crate::compact::MemoryReport;
+use std::io::BufRead;
+use std::sync::Arc;
 
 pub struct Engine {
     pub blocker: Blocker,
@@ -23,6 +25,16 @@ impl Engine {
     }
 
     pub fn from_rules_parametrised(network_filters: &[String], debug: bool, optimize: bool) -> Engine {
+        Self::from_rules_with_token_profile(network_filters, debug, optimize, None)
+    }
+
+    /**
+     * Builds the engine choosing, for each filter, the token found in the fewest
+     * requests of `token_profile`, e.g. one gathered with
+     * `TokenProfile::from_urls` over a sample of real traffic. The profile is
+     * stored with the serialized engine.
+     */
+    pub fn from_rules_with_token_profile(network_filters: &[String], debug: bool, optimize: bool, token_profile: Option<TokenProfile>) -> Engine {
         let (parsed_network_filters, _) = parse_filters(&network_filters, true, false, debug);
 
         let blocker_options = BlockerOptions {
@@ -33,34 +45,56 @@ impl Engine {
         };
 
         Engine {
-            blocker: Blocker::new(parsed_network_filters, &blocker_options),
+            blocker: Blocker::new_with_token_profile(parsed_network_filters, &blocker_options, token_profile),
         }
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, BlockerError> {
-        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
-        rmps::encode::write(&mut gz, &self.blocker)
-            .or_else(|e| {
-                eprintln!("Error serializing: {:?}", e);
-                Err(BlockerError::SerializationError)
-            })?;
-
-        let compressed = gz.finish()
-            .or_else(|_| Err(BlockerError::SerializationError))?;
-        Ok(compressed)
+        serialization::serialize(&self.blocker)
     }
 
     pub fn deserialize(&mut self, serialized: &[u8]) -> Result<(), BlockerError> {
+        if serialized.starts_with(&serialization::INDEXED_MAGIC) {
+            return self.deserialize_indexed::<Vec<u8>>(serialized.into());
+        }
+        let blocker = serialization::deserialize(serialized)?;
+        self.replace_blocker(blocker);
+        Ok(())
+    }
+
+    /// Serializes the engine in a layout that can be loaded without decoding
+    /// all filters up front, see `deserialize_indexed`.
+    pub fn serialize_indexed(&self) -> Result<Vec<u8>, BlockerError> {
+        serialization::serialize_indexed(&self.blocker)
+    }
+
+    /**
+     * Loads an engine serialized with `serialize_indexed`. Filters are only
+     * decoded once a request needs them, so `data` is kept around and can be
+     * e.g. a memory-mapped file, avoiding reading the whole list at startup.
+     */
+    pub fn deserialize_indexed<D: AsRef<[u8]> + 'static>(&mut self, data: D) -> Result<(), BlockerError> {
+        let blocker = serialization::deserialize_indexed(data)?;
+        self.replace_blocker(blocker);
+        Ok(())
+    }
+
+    fn replace_blocker(&mut self, blocker: Blocker) {
         let current_tags = self.blocker.tags_enabled();
-        let gz = GzDecoder::new(serialized);
-        let blocker = rmps::decode::from_read(gz)
-            .or_else(|e| {
-                eprintln!("Error deserializing: {:?}", e);
-                Err(BlockerError::DeserializationError)
-            })?;
+        let current_lists_disabled = self.blocker.lists_disabled();
+        let current_observer = self.blocker.observer();
+        let current_hot_filters = self.blocker.hot_filter_options();
+        let current_result_cache = self.blocker.result_cache_capacity();
+        let current_regex_limits = self.blocker.regex_limits();
         self.blocker = blocker;
+        self.blocker.with_regex_limits(current_regex_limits);
+        self.blocker.with_hot_filters(current_hot_filters);
+        self.blocker.with_result_cache(current_result_cache);
         self.blocker.with_tags(&current_tags.iter().map(|s| &**s).collect::<Vec<_>>());
-        Ok(())
+        self.blocker.lists_disable(&current_lists_disabled.iter().map(|s| &**s).collect::<Vec<_>>());
+        if let Some(observer) = current_observer {
+            self.blocker.with_observer(observer);
+        }
     }
 
     pub fn check_network_urls(&self, url: &str, source_url: &str, request_type: &str) -> BlockerResult {
@@ -70,13 +104,7 @@ impl Engine {
         })
         .unwrap_or_else(|_e| {
             eprintln!("Error parsing request, returning no match");
-            BlockerResult {
-                matched: false,
-                explicit_cancel: false,
-                redirect: None,
-                exception: None,
-                filter: None,
-            }
+            BlockerResult::default()
         })
         
     }
@@ -140,6 +168,39 @@ impl Engine {
         self.blocker.tags_enabled().contains(&tag.to_owned())
     }
 
+    /// Turns off the filters of lists added with `EngineBuilder`, see `Blocker::lists_disable`.
+    pub fn lists_disable(&mut self, lists: &[&str]) -> &mut Engine {
+        self.blocker.lists_disable(lists);
+        self
+    }
+
+    pub fn lists_enable(&mut self, lists: &[&str]) -> &mut Engine {
+        self.blocker.lists_enable(lists);
+        self
+    }
+
+    pub fn list_enabled(&self, list: &str) -> bool {
+        !self.blocker.lists_disabled().iter().any(|disabled| disabled == list)
+    }
+
+    /**
+     * Replaces the filters of list `id` with those of `rules`, e.g. after the
+     * list was updated, rebuilding only what changed, see `Blocker::replace_list`.
+     * On `BlockerError::BadFilterTargetOptimized` the engine is left unchanged and
+     * has to be built again from all lists.
+     */
+    pub fn replace_list(&mut self, id: &str, rules: &[String], options: ListOptions) -> Result<&mut Engine, BlockerError> {
+        let (network_filters, _) = parse_filters_for_list(rules, id, true, false, options.debug);
+        let network_filters = network_filters.into_iter().filter(|filter| options.allows(filter)).collect();
+        self.blocker.replace_list(id, network_filters)?;
+        if options.enabled {
+            self.lists_enable(&[id]);
+        } else {
+            self.lists_disable(&[id]);
+        }
+        Ok(self)
+    }
+
     pub fn with_resources<'a>(&'a mut self, resources: &'a str) -> &'a mut Engine {
         let resources = Resources::parse(resources);
         self.blocker.with_resources(resources);
@@ -158,12 +219,190 @@ impl Engine {
     pub fn resource_get(&self, key: &str) -> Option<Resource> {
         self.blocker.resource_get(key).cloned()
     }
+
+    pub fn with_observer(&mut self, observer: Arc<dyn BlockerObserver>) -> &mut Engine {
+        self.blocker.with_observer(observer);
+        self
+    }
+
+    /// Limits the size of the regexes compiled for this engine's filters, see `RegexLimits`.
+    pub fn with_regex_limits(&mut self, limits: RegexLimits) -> &mut Engine {
+        self.blocker.with_regex_limits(limits);
+        self
+    }
+
+    pub fn with_hot_filters(&mut self, options: HotFilterOptions) -> &mut Engine {
+        self.blocker.with_hot_filters(options);
+        self
+    }
+
+    /// Caches the results of recent requests, see `Blocker::with_result_cache`.
+    pub fn with_result_cache(&mut self, capacity: usize) -> &mut Engine {
+        self.blocker.with_result_cache(capacity);
+        self
+    }
+
+    /// Shares equal data between filters added since the engine was built, see `Blocker::compact`.
+    pub fn compact(&mut self) {
+        self.blocker.compact();
+    }
+
+    /// Filters, buckets, regexes and memory of each list, see `Blocker::stats`.
+    pub fn stats(&self) -> BlockerStats {
+        self.blocker.stats()
+    }
+
+    /// Memory used by the filters of each list, see `Blocker::memory_report`.
+    pub fn memory_report(&self) -> MemoryReport {
+        self.blocker.memory_report()
+    }
+
+    /// Checks whether all requests to `hostname` are blocked, see `Blocker::is_hostname_blocked`.
+    pub fn is_hostname_blocked(&self, hostname: &str) -> bool {
+        self.blocker.is_hostname_blocked(hostname)
+    }
+
+    /// Compiles all regexes up front, returning the filters whose regex failed to compile.
+    pub fn warm_up_regexes(&self) -> Vec<String> {
+        self.blocker.warm_up_regexes()
+    }
 }
 
+/// How a list given to `EngineBuilder::add_list` is loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListOptions {
+    /// Keep the rules as written, see `Engine::from_rules_debug`. Debug output
+    /// of the engine itself is not tied to a list, it is turned on for the whole
+    /// engine as soon as one list asks for it
+    pub debug: bool,
+    /// Untrusted lists may only block and allow requests: their `$csp` and
+    /// `$redirect` filters, which change the content of pages, are left out
+    pub trusted: bool,
+    /// Whether the filters of the list apply once the engine is built
+    pub enabled: bool,
+}
+
+impl ListOptions {
+    fn allows(&self, filter: &NetworkFilter) -> bool {
+        self.trusted || !(filter.is_csp() || filter.is_redirect())
+    }
+}
+
+impl Default for ListOptions {
+    fn default() -> ListOptions {
+        ListOptions {
+            debug: false,
+            trusted: true,
+            enabled: true,
+        }
+    }
+}
+
+/**
+ * Builds an `Engine` from several lists, each with its own `ListOptions`.
+ * Filters remember the list they come from, so that `Engine::lists_disable`
+ * and `Engine::lists_enable` can turn whole lists off and on again without
+ * parsing anything, and `BlockerResult::filter_list` names the list that
+ * blocked a request.
+ */
+pub struct EngineBuilder {
+    network_filters: Vec<NetworkFilter>,
+    lists_disabled: Vec<String>,
+    debug: bool,
+    optimize: bool,
+    token_profile: Option<TokenProfile>,
+}
+
+impl Default for EngineBuilder {
+    fn default() -> EngineBuilder {
+        EngineBuilder {
+            network_filters: Vec::new(),
+            lists_disabled: Vec::new(),
+            debug: false,
+            optimize: true,
+            token_profile: None,
+        }
+    }
+}
+
+impl EngineBuilder {
+    pub fn new() -> EngineBuilder {
+        EngineBuilder::default()
+    }
+
+    /// Parses the rules of list `id`, e.g. the `uuid` of a `FilterList`.
+    pub fn add_list(&mut self, id: &str, rules: &[String], options: ListOptions) -> &mut EngineBuilder {
+        let (network_filters, _) = parse_filters_for_list(rules, id, true, false, options.debug);
+        self.add_filters(id, network_filters, options)
+    }
+
+    /// Adds filters of list `id` that were already parsed, e.g. with `lists::parse_filters_for_list`.
+    pub fn add_filters(&mut self, id: &str, network_filters: Vec<NetworkFilter>, options: ListOptions) -> &mut EngineBuilder {
+        self.network_filters.extend(network_filters.into_iter().filter(|filter| options.allows(filter)));
+        self.add_list_options(id, options)
+    }
+
+    /**
+     * Parses the rules of list `id` as they are read from `reader`, see
+     * `lists::NetworkFilterReader`, without holding all the lines of the list
+     * in memory at once. Only the text is streamed: the parsed filters are kept
+     * until `build`, like those of `add_list`. Rejected lines are dropped, use
+     * a `NetworkFilterReader` and `add_filters` to look at its diagnostics.
+     */
+    pub fn add_list_from_reader<R: BufRead>(&mut self, id: &str, reader: R, options: ListOptions) -> std::io::Result<&mut EngineBuilder> {
+        for filter in NetworkFilterReader::new(reader, options.debug).for_list(id) {
+            let filter = filter?;
+            if options.allows(&filter) {
+                self.network_filters.push(filter);
+            }
+        }
+        Ok(self.add_list_options(id, options))
+    }
+
+    fn add_list_options(&mut self, id: &str, options: ListOptions) -> &mut EngineBuilder {
+        self.debug |= options.debug;
+        if !options.enabled {
+            self.lists_disabled.push(String::from(id));
+        }
+        self
+    }
+
+    pub fn with_optimizations(&mut self, optimize: bool) -> &mut EngineBuilder {
+        self.optimize = optimize;
+        self
+    }
+
+    /// See `Engine::from_rules_with_token_profile`.
+    pub fn with_token_profile(&mut self, token_profile: TokenProfile) -> &mut EngineBuilder {
+        self.token_profile = Some(token_profile);
+        self
+    }
+
+    /// Builds the engine from all the lists added so far, leaving the builder empty.
+    pub fn build(&mut self) -> Engine {
+        let blocker_options = BlockerOptions {
+            debug: self.debug,
+            enable_optimizations: self.optimize,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+        let network_filters = std::mem::take(&mut self.network_filters);
+        let mut engine = Engine {
+            blocker: Blocker::new_with_token_profile(network_filters, &blocker_options, self.token_profile.take()),
+        };
+        let lists_disabled = std::mem::take(&mut self.lists_disabled);
+        engine.lists_disable(&lists_disabled.iter().map(|s| &**s).collect::<Vec<_>>());
+        engine
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<String> {
+        rules.iter().map(|rule| String::from(*rule)).collect()
+    }
     
     #[test]
     fn tags_enable_adds_tags() {
@@ -370,6 +609,364 @@ mod tests {
         assert_eq!(matched_rule.redirect, Some("data:text/plain;base64,".to_owned()), "Expected redirect to contain resource");
     }
 
+    #[test]
+    fn serialization_is_deterministic() {
+        let rules = crate::utils::read_file_lines("data/test/easyprivacy.txt");
+        let resources = crate::utils::read_file_lines("data/uBlockOrigin/resources.txt").join("\n");
+
+        let mut engine = Engine::from_rules(&rules);
+        engine.with_resources(&resources);
+        let serialized = engine.serialize().unwrap();
+        assert_eq!(serialized, engine.serialize().unwrap(), "Serializing the same engine twice differs");
+
+        let mut other_engine = Engine::from_rules(&rules);
+        other_engine.with_resources(&resources);
+        assert_eq!(serialized, other_engine.serialize().unwrap(), "Serializing engines built from the same rules differs");
+    }
+
+    #[test]
+    fn serialization_retains_token_profile() {
+        let profile = TokenProfile::from_urls([
+            "https://example.com/banner/1.png",
+            "https://example.com/banner/2.png",
+        ]);
+        let engine = Engine::from_rules_with_token_profile(&["/banner/ads/pixel".to_owned()], false, true, Some(profile.clone()));
+        assert_eq!(engine.blocker.token_profile(), Some(&profile));
+
+        let mut deserialized = Engine::from_rules(&[]);
+        deserialized.deserialize(&engine.serialize().unwrap()).unwrap();
+        assert_eq!(deserialized.blocker.token_profile(), Some(&profile));
+        deserialized.filter_add("/banner/track/pixel");
+        assert!(deserialized.check_network_urls("https://example.com/banner/track/pixel.gif", "", "").matched);
+        assert!(deserialized.check_network_urls("https://example.com/banner/ads/pixel.gif", "", "").matched);
+
+        let mut indexed = Engine::from_rules(&[]);
+        indexed.deserialize_indexed(engine.serialize_indexed().unwrap()).unwrap();
+        assert_eq!(indexed.blocker.token_profile(), Some(&profile));
+
+        let plain = Engine::from_rules(&["/banner/ads/pixel".to_owned()]);
+        deserialized.deserialize(&plain.serialize().unwrap()).unwrap();
+        assert_eq!(deserialized.blocker.token_profile(), None);
+    }
+
+    #[test]
+    fn results_name_the_list_of_the_filter() {
+        let (mut filters, _) = crate::lists::parse_filters_for_list(&rules(&["/ads/banner", "/ads/popup"]), "easylist", true, false, false);
+        let (regional, _) = crate::lists::parse_filters_for_list(&rules(&["/ads/pixel", "@@/ads/banner/ok"]), "regional", true, false, false);
+        filters.extend(regional);
+        let blocker_options = BlockerOptions {
+            debug: false,
+            enable_optimizations: true,
+            load_cosmetic_filters: false,
+            load_network_filters: true,
+        };
+        let engine = Engine { blocker: Blocker::new(filters, &blocker_options) };
+
+        let mut deserialized = Engine::from_rules(&[]);
+        deserialized.deserialize(&engine.serialize().unwrap()).unwrap();
+        let mut indexed = Engine::from_rules(&[]);
+        indexed.deserialize_indexed(engine.serialize_indexed().unwrap()).unwrap();
+
+        for engine in [&engine, &deserialized, &indexed].iter() {
+            let result = engine.check_network_urls("https://example.com/ads/popup.js", "", "");
+            assert!(result.matched);
+            assert_eq!(result.filter_list.as_deref(), Some("easylist"));
+
+            let result = engine.check_network_urls("https://example.com/ads/pixel.gif", "", "");
+            assert_eq!(result.filter_list.as_deref(), Some("regional"));
+
+            let result = engine.check_network_urls("https://example.com/ads/banner/ok.gif", "", "");
+            assert!(!result.matched);
+            assert_eq!(result.filter_list.as_deref(), Some("easylist"));
+            assert_eq!(result.exception_list.as_deref(), Some("regional"));
+        }
+
+        let result = Engine::from_rules(&rules(&["/ads/banner"])).check_network_urls("https://example.com/ads/banner.gif", "", "");
+        assert!(result.matched);
+        assert_eq!(result.filter_list, None);
+    }
+
+    #[test]
+    fn builder_turns_lists_on_and_off() {
+        let mut engine = EngineBuilder::new()
+            .add_list("easylist", &rules(&["/ads/banner", "||tracker.com^"]), ListOptions::default())
+            .add_list("regional", &rules(&["/ads/pixel", "@@||tracker.com/ok"]), ListOptions::default())
+            .add_list("annoyances", &rules(&["/popup/"]), ListOptions { enabled: false, ..ListOptions::default() })
+            .build();
+        engine.with_result_cache(16);
+
+        let check = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://example.com", "").matched;
+        assert!(check(&engine, "https://example.com/ads/banner.gif"));
+        assert!(check(&engine, "https://example.com/ads/pixel.gif"));
+        assert!(!check(&engine, "https://example.com/popup/1.html"));
+        assert!(!check(&engine, "https://tracker.com/ok.js"));
+        assert!(!engine.list_enabled("annoyances"));
+
+        engine.lists_disable(&["regional"]).lists_enable(&["annoyances"]);
+        assert!(check(&engine, "https://example.com/ads/banner.gif"));
+        assert!(!check(&engine, "https://example.com/ads/pixel.gif"));
+        assert!(check(&engine, "https://example.com/popup/1.html"));
+        assert!(check(&engine, "https://tracker.com/ok.js"));
+        assert!(engine.is_hostname_blocked("tracker.com"));
+
+        let serialized = engine.serialize().unwrap();
+        engine.deserialize(&serialized).unwrap();
+        assert!(!engine.list_enabled("regional"));
+        assert!(!check(&engine, "https://example.com/ads/pixel.gif"));
+
+        engine.lists_disable(&["easylist"]);
+        assert!(!check(&engine, "https://example.com/ads/banner.gif"));
+        assert!(!engine.is_hostname_blocked("tracker.com"));
+    }
+
+    #[test]
+    fn builder_leaves_out_page_changing_filters_of_untrusted_lists() {
+        let rules = vec![
+            String::from("/ads/$redirect=nooptext"),
+            String::from("/track$csp=script-src 'none'"),
+            String::from("/ads/banner"),
+        ];
+        let untrusted = EngineBuilder::new()
+            .add_list("custom", &rules, ListOptions { trusted: false, ..ListOptions::default() })
+            .build();
+        let stats = untrusted.stats();
+        assert_eq!((stats.redirects.filters, stats.csp.filters, stats.filters.filters), (0, 0, 1));
+
+        let trusted = EngineBuilder::new().add_list("custom", &rules, ListOptions::default()).build();
+        let stats = trusted.stats();
+        assert_eq!((stats.redirects.filters, stats.csp.filters, stats.filters.filters), (1, 1, 1));
+    }
+
+    #[test]
+    fn replace_list_only_changes_that_list() {
+        let easylist = rules(&["/ads/banner", "||ads.com^", "@@||ads.com/ok$image"]);
+        let old_privacy = rules(&["/track/pixel", "||tracker.com^", "/beacon.js$script", "||tagged.com^$tag=stuff"]);
+        let new_privacy = rules(&["/track/pixel", "||metrics.com^", "/collect?$xhr", "@@/track/pixel/ok", "||tagged.com^$tag=stuff"]);
+        let urls = [
+            ("https://example.com/ads/banner.gif", "image"),
+            ("https://ads.com/ok.png", "image"),
+            ("https://ads.com/script.js", "script"),
+            ("https://example.com/track/pixel.gif", "image"),
+            ("https://example.com/track/pixel/ok.gif", "image"),
+            ("https://tracker.com/a.js", "script"),
+            ("https://metrics.com/a.js", "script"),
+            ("https://example.com/beacon.js", "script"),
+            ("https://example.com/collect?id=1", "xmlhttprequest"),
+            ("https://tagged.com/a.js", "script"),
+        ];
+
+        let mut engine = EngineBuilder::new()
+            .add_list("easylist", &easylist, ListOptions::default())
+            .add_list("easyprivacy", &old_privacy, ListOptions::default())
+            .build();
+        engine.tags_enable(&["stuff"]);
+        let mut indexed = Engine::from_rules(&[]);
+        indexed.deserialize_indexed(engine.serialize_indexed().unwrap()).unwrap();
+        indexed.tags_enable(&["stuff"]);
+        let mut expected = EngineBuilder::new()
+            .add_list("easylist", &easylist, ListOptions::default())
+            .add_list("easyprivacy", &new_privacy, ListOptions::default())
+            .build();
+        expected.tags_enable(&["stuff"]);
+        assert!(expected.check_network_urls("https://tagged.com/a.js", "https://example.com", "script").matched);
+
+        engine.replace_list("easyprivacy", &new_privacy, ListOptions::default()).unwrap();
+        indexed.replace_list("easyprivacy", &new_privacy, ListOptions::default()).unwrap();
+        for (url, request_type) in urls.iter() {
+            let expected = expected.check_network_urls(url, "https://example.com", request_type);
+            for engine in [&engine, &indexed].iter() {
+                let result = engine.check_network_urls(url, "https://example.com", request_type);
+                assert_eq!(result.matched, expected.matched, "{}", url);
+                assert_eq!(result.filter_list, expected.filter_list, "{}", url);
+                assert_eq!(result.exception_list, expected.exception_list, "{}", url);
+            }
+        }
+        assert_eq!(engine.stats().filters.filters, expected.stats().filters.filters);
+    }
+
+    #[test]
+    fn replace_list_applies_badfilters_across_lists() {
+        let easylist = rules(&["||ads.com^", "||tracker.com^", "/banner/"]);
+        let mut engine = EngineBuilder::new()
+            .add_list("easylist", &easylist, ListOptions::default())
+            .add_list("fixes", &rules(&["||ads.com^$badfilter"]), ListOptions::default())
+            .build();
+        let blocked = |engine: &Engine, url: &str| engine.check_network_urls(url, "", "").matched;
+        assert!(!blocked(&engine, "https://ads.com/a.js"));
+
+        engine.replace_list("fixes", &rules(&["||tracker.com^$badfilter"]), ListOptions::default()).unwrap();
+        assert!(blocked(&engine, "https://ads.com/a.js"));
+        assert!(!blocked(&engine, "https://tracker.com/a.js"));
+
+        // the disabled filter stays disabled when its own list changes
+        engine.replace_list("easylist", &easylist, ListOptions::default()).unwrap();
+        assert!(blocked(&engine, "https://ads.com/a.js"));
+        assert!(!blocked(&engine, "https://tracker.com/a.js"));
+
+        let mut deserialized = Engine::from_rules(&[]);
+        deserialized.deserialize(&engine.serialize().unwrap()).unwrap();
+        deserialized.replace_list("fixes", &[], ListOptions::default()).unwrap();
+        assert!(blocked(&deserialized, "https://tracker.com/a.js"));
+        assert!(blocked(&deserialized, "https://example.com/banner/1.gif"));
+    }
+
+    #[test]
+    fn replace_list_refuses_badfilters_of_fused_filters() {
+        let mut engine = EngineBuilder::new()
+            .add_list("easylist", &rules(&["-ads-", ".ads."]), ListOptions::default())
+            .add_list("fixes", &[], ListOptions::default())
+            .build();
+        assert_eq!(engine.stats().filters.filters, 1);
+
+        let result = engine.replace_list("fixes", &rules(&["-ads-$badfilter"]), ListOptions::default());
+        assert_eq!(result.err(), Some(BlockerError::BadFilterTargetOptimized));
+        assert!(engine.check_network_urls("https://example.com/x-ads-1.gif", "", "").matched);
+
+        let mut engine = EngineBuilder::new()
+            .add_list("easylist", &rules(&["-ads-$domain=a.com|c.com", "-ads-$domain=b.com|c.com"]), ListOptions::default())
+            .build();
+        let result = engine.replace_list("fixes", &rules(&["-ads-$domain=c.com|a.com,badfilter"]), ListOptions::default());
+        assert_eq!(result.err(), Some(BlockerError::BadFilterTargetOptimized));
+
+        // without optimizations, filters can always be found
+        let mut engine = EngineBuilder::new()
+            .with_optimizations(false)
+            .add_list("easylist", &rules(&["-ads-", ".ads."]), ListOptions::default())
+            .build();
+        engine.replace_list("fixes", &rules(&["-ads-$badfilter"]), ListOptions::default()).unwrap();
+        assert!(!engine.check_network_urls("https://example.com/x-ads-1.gif", "", "").matched);
+        assert!(engine.check_network_urls("https://example.com/x.ads.gif", "", "").matched);
+    }
+
+    #[test]
+    fn builder_reads_lists_from_readers() {
+        let list = crate::utils::read_file_lines("data/test/easylist.txt");
+        let file = std::fs::File::open("data/test/easylist.txt").unwrap();
+        let mut builder = EngineBuilder::new();
+        builder.add_list_from_reader("easylist", std::io::BufReader::new(file), ListOptions::default()).unwrap();
+        let streamed = builder.build();
+        let parsed = EngineBuilder::new().add_list("easylist", &list, ListOptions::default()).build();
+
+        assert_eq!(streamed.serialize().unwrap(), parsed.serialize().unwrap());
+        let result = streamed.check_network_urls("https://example.com/adbanner.gif", "https://example.com", "image");
+        assert!(result.matched);
+        assert_eq!(result.filter_list.as_deref(), Some("easylist"));
+    }
+
+    #[test]
+    fn stats_describe_lists() {
+        let mut engine = Engine::from_rules_parametrised(&[
+            "/ads/banner".to_owned(),
+            "/ads/popup".to_owned(),
+            "/ads/*/pixel".to_owned(),
+            "||tracker.com^".to_owned(),
+            "@@/ads/ok".to_owned(),
+            "/track$csp=script-src 'none'".to_owned(),
+            "/ads/$redirect=nooptext,image".to_owned(),
+            "ads$tag=stuff".to_owned(),
+        ], false, false);
+        engine.with_resources("nooptext text/plain\n\n");
+
+        let stats = engine.stats();
+        assert_eq!(stats.filters.filters, 4);
+        assert_eq!(stats.filters.bucket_entries, 4);
+        assert_eq!(stats.filters.buckets, 2);
+        assert_eq!(stats.filters.largest_buckets, vec![(crate::utils::fast_hash("ads"), 3), (crate::utils::fast_hash("tracker.com"), 1)]);
+        assert_eq!(stats.filters.regex_filters, 1);
+        assert_eq!(stats.filters.compiled_regexes, 0);
+        assert_eq!(stats.exceptions.filters, 1);
+        assert_eq!(stats.csp.filters, 1);
+        assert_eq!(stats.redirects.filters, 1);
+        assert_eq!(stats.filters_tagged.filters, 0);
+        assert_eq!(stats.resource_bytes, "nooptext".len() + "text/plain".len());
+        assert_eq!(stats.memory.total().filters, 8);
+
+        assert!(engine.check_network_urls("https://example.com/ads/1/pixel.gif", "", "").matched);
+        engine.tags_enable(&["stuff"]);
+        let stats = engine.stats();
+        assert_eq!(stats.filters.compiled_regexes, 1);
+        assert!(stats.filters.compiled_regex_bytes > 0);
+        assert_eq!(stats.filters_tagged.filters, 1);
+    }
+
+    #[test]
+    fn indexed_serialization_matches_same() {
+        let rules = crate::utils::read_file_lines("data/test/easyprivacy.txt");
+        let mut engine = Engine::from_rules(&rules);
+        engine.with_tags(&["stuff"]);
+        let serialized = engine.serialize().unwrap();
+
+        let mut indexed_engine = Engine::from_rules(&[]);
+        indexed_engine.with_tags(&["stuff"]);
+        indexed_engine.deserialize_indexed(engine.serialize_indexed().unwrap()).unwrap();
+        assert_eq!(indexed_engine.serialize().unwrap(), serialized, "Lazily loaded engine does not contain the same filters");
+
+        let urls = [
+            ("https://www.google-analytics.com/analytics.js", "https://example.com"),
+            ("https://example.com/pixel.gif?utm_source=x", "https://example.com"),
+            ("https://example.com/main.js", "https://example.com"),
+        ];
+        for (url, source_url) in urls.iter() {
+            let expected = engine.check_network_urls(url, source_url, "script");
+            let result = indexed_engine.check_network_urls(url, source_url, "script");
+            assert_eq!(result.matched, expected.matched, "Expected match = {} for {}", expected.matched, url);
+            assert_eq!(result.filter, expected.filter);
+        }
+
+        indexed_engine.filter_add("||example.com/main.js");
+        assert!(indexed_engine.check_network_urls("https://example.com/main.js", "https://example.com", "script").matched);
+    }
+
+    #[test]
+    fn match_case_filters_match_original_case() {
+        let engine = Engine::from_rules(&[
+            "/AdBlock.js$match-case".to_owned(),
+            "||foo.com/Detect^$match-case,script".to_owned(),
+        ]);
+
+        assert!(engine.check_network_urls("https://example.com/AdBlock.js", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://example.com/adblock.js", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://foo.com/Detect?x", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://foo.com/detect?x", "https://example.com", "script").matched);
+    }
+
+    #[test]
+    fn result_cache_is_invalidated() {
+        let mut engine = Engine::from_rules(&[
+            "ad-banner$redirect=nooptext".to_owned(),
+            "tracker$tag=stuff".to_owned(),
+        ]);
+        engine.with_result_cache(16);
+
+        let banner = "http://example.com/ad-banner.gif";
+        assert_eq!(engine.check_network_urls(banner, "", "").redirect, None);
+        assert_eq!(engine.check_network_urls(banner, "", "").redirect, None);
+        engine.with_resources("nooptext text/plain\n\n");
+        assert_eq!(engine.check_network_urls(banner, "", "").redirect, Some("data:text/plain;base64,".to_owned()));
+
+        let tracker = "http://example.com/tracker.js";
+        assert!(!engine.check_network_urls(tracker, "", "").matched);
+        engine.tags_enable(&["stuff"]);
+        assert!(engine.check_network_urls(tracker, "", "").matched);
+        engine.tags_disable(&["stuff"]);
+        assert!(!engine.check_network_urls(tracker, "", "").matched);
+
+        engine.filter_add("/Case.js$match-case");
+        assert!(engine.check_network_urls("https://example.com/Case.js", "", "").matched);
+        assert!(!engine.check_network_urls("https://example.com/case.js", "", "").matched);
+
+        let script = "https://example.com/main.js";
+        assert!(!engine.check_network_urls(script, "https://example.com", "script").matched);
+        engine.filter_add("||example.com/main.js");
+        assert!(engine.check_network_urls(script, "https://example.com", "script").matched);
+
+        let empty = Engine::from_rules(&[]).serialize().unwrap();
+        engine.deserialize(&empty).unwrap();
+        assert_eq!(engine.blocker.result_cache_capacity(), 16);
+        assert!(!engine.check_network_urls(script, "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls(banner, "", "").matched);
+    }
+
     fn deserialization_generate_simple() {
         let engine = Engine::from_rules(&[
             "ad-banner".to_owned()
@@ -419,6 +1016,25 @@ noopcss text/css
         assert_eq!(matched_rule.redirect, Some("data:text/plain;base64,".to_owned()), "Expected redirect to contain resource");
     }
 
+    #[test]
+    fn matched_filters_are_printed_without_debug() {
+        let engine = Engine::from_rules_parametrised(&[
+            String::from("||example.com/ads/$third-party,image"),
+            String::from("@@||example.com/ads/good$image,domain=b.com|a.com"),
+        ], false, true);
+        let serialized = engine.serialize().unwrap();
+        let mut deserialized = Engine::from_rules(&[]);
+        deserialized.deserialize(&serialized).unwrap();
+
+        let matched_rule = deserialized.check_network_urls("https://example.com/ads/banner.png", "https://a.com", "image");
+        assert!(matched_rule.matched);
+        assert_eq!(matched_rule.filter, Some(String::from("||example.com/ads/$image,third-party")));
+
+        let matched_rule = deserialized.check_network_urls("https://example.com/ads/good.png", "https://a.com", "image");
+        assert!(!matched_rule.matched);
+        assert_eq!(matched_rule.exception, Some(String::from("@@||example.com/ads/good$image,domain=a.com|b.com")));
+    }
+
     #[test]
     fn redirect_resource_lookup_works() {
         let script = r#"
@@ -1 +1,4 @@
 pub mod network;
+pub mod wildcard;
+#[cfg(feature = "multi-pattern-matching")]
+pub mod multi_pattern;
@@ -0,0 +1,95 @@
+//! Matching of many plain filter patterns with a single scan of the URL.
+
+use aho_corasick::AhoCorasick;
+use hashbrown::HashMap;
+
+use crate::filters::network::NetworkFilter;
+
+/// Below this number of plain patterns, searching for each one separately is just as fast.
+pub const MIN_PLAIN_PATTERNS: usize = 4;
+
+/**
+ * Plain patterns of a set of filters compiled into one Aho-Corasick automaton,
+ * so that the URL is scanned once rather than once per filter. Filters keep
+ * their order; `pattern_of` tells which pattern, if any, stands for each of them.
+ */
+pub struct PlainPatterns {
+    automaton: AhoCorasick,
+    pattern_ids: Vec<Option<usize>>,
+}
+
+impl PlainPatterns {
+    /// Returns `None` when fewer than `MIN_PLAIN_PATTERNS` filters have a plain pattern.
+    pub fn new<'a, I: Iterator<Item = &'a NetworkFilter>>(filters: I) -> Option<PlainPatterns> {
+        let mut patterns: Vec<&str> = Vec::new();
+        let mut ids: HashMap<&str, usize> = HashMap::new();
+        let mut plain_filters = 0;
+        let pattern_ids: Vec<Option<usize>> = filters
+            .map(|filter| {
+                filter.plain_pattern().map(|pattern| {
+                    plain_filters += 1;
+                    *ids.entry(pattern).or_insert_with(|| {
+                        patterns.push(pattern);
+                        patterns.len() - 1
+                    })
+                })
+            })
+            .collect();
+
+        if plain_filters < MIN_PLAIN_PATTERNS {
+            return None;
+        }
+
+        Some(PlainPatterns {
+            automaton: AhoCorasick::new_auto_configured(&patterns),
+            pattern_ids,
+        })
+    }
+
+    /// Which patterns occur anywhere in `url`, indexed by pattern.
+    pub fn find(&self, url: &str) -> Vec<bool> {
+        let mut found = vec![false; self.automaton.pattern_count()];
+        for m in self.automaton.find_overlapping_iter(url) {
+            found[m.pattern()] = true;
+        }
+        found
+    }
+
+    /// Pattern standing for the `i`-th filter, or `None` if its pattern has to be checked on its own.
+    pub fn pattern_of(&self, i: usize) -> Option<usize> {
+        self.pattern_ids.get(i).cloned().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(rules: &[&str]) -> Vec<NetworkFilter> {
+        rules.iter().map(|rule| NetworkFilter::parse(rule, true).unwrap()).collect()
+    }
+
+    #[test]
+    fn skips_small_sets() {
+        let filters = parse(&["adbanner/", "/ads", "||foo.com^", "/banner*/ads", "/ads"]);
+        assert!(PlainPatterns::new(filters.iter()).is_none());
+    }
+
+    #[test]
+    fn finds_all_occurring_patterns() {
+        let filters = parse(&["adbanner/", "||foo.com^", "banner/", "/ads", "/ads$script", "ads/", "/track?"]);
+        let patterns = PlainPatterns::new(filters.iter()).unwrap();
+
+        assert_eq!(patterns.pattern_of(1), None);
+        assert_eq!(patterns.pattern_of(3), patterns.pattern_of(4));
+        assert_eq!(patterns.pattern_of(7), None);
+
+        let found = patterns.find("https://example.com/adbanner/ads/image.png");
+        let occurs = |i: usize| found[patterns.pattern_of(i).unwrap()];
+        assert!(occurs(0));
+        assert!(occurs(2));
+        assert!(occurs(3));
+        assert!(occurs(5));
+        assert!(!occurs(6));
+    }
+}
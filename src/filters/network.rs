@@ -1,14 +1,16 @@
 use idna;
-use regex::Regex;
-use regex::RegexSet;
+use regex::{Regex, RegexBuilder};
+use regex::{RegexSet, RegexSetBuilder};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
 use crate::request;
 use crate::utils;
 use crate::utils::Hash;
+use crate::filters::wildcard::WildcardPattern;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::Arc;
 use twoway;
 
 pub const TOKENS_BUFFER_SIZE: usize = 200;
@@ -98,6 +100,8 @@ bitflags! {
 pub enum CompiledRegex {
     Compiled(Regex),
     CompiledSet(RegexSet),
+    /// Patterns made of literals, `*` and `^` only, matched without a regex engine
+    Wildcard(Vec<WildcardPattern>),
     MatchAll,
     RegexParsingError(regex::Error),
 }
@@ -113,6 +117,7 @@ impl CompiledRegex {
                 // println!("Matching {} against RegexSet: {:?}", pattern, matches);
                 r.is_match(pattern)
             }
+            CompiledRegex::Wildcard(patterns) => patterns.iter().any(|p| p.is_match(pattern)),
         }
     }
 
@@ -122,6 +127,27 @@ impl CompiledRegex {
             CompiledRegex::RegexParsingError(_e) => String::from("ERROR"), // no match if regex didn't even compile
             CompiledRegex::Compiled(r) => String::from(r.as_str()),
             CompiledRegex::CompiledSet(r) => r.patterns().join(" | "),
+            CompiledRegex::Wildcard(patterns) => patterns.iter().map(|p| p.to_regex_string()).collect::<Vec<_>>().join(" | "),
+        }
+    }
+}
+
+/// Limits applied when compiling filter patterns to regexes, so that a hostile
+/// filter list cannot make the engine allocate arbitrary amounts of memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegexLimits {
+    /// Approximate size limit, in bytes, of a single compiled regex or regex set
+    pub size_limit: usize,
+    /// Approximate size limit, in bytes, of the cache used by the lazy DFA of each regex
+    pub dfa_size_limit: usize,
+}
+
+impl Default for RegexLimits {
+    // Same as the `regex` crate defaults
+    fn default() -> RegexLimits {
+        RegexLimits {
+            size_limit: 10 * (1 << 20),
+            dfa_size_limit: 2 * (1 << 20),
         }
     }
 }
@@ -177,13 +203,13 @@ impl FilterPart {
 pub struct NetworkFilter {
     pub mask: NetworkFilterMask,
     pub filter: FilterPart,
-    pub opt_domains: Option<Vec<Hash>>,
-    pub opt_not_domains: Option<Vec<Hash>>,
-    pub redirect: Option<String>,
-    pub hostname: Option<String>,
-    pub csp: Option<String>,
+    pub opt_domains: Option<Arc<[Hash]>>,
+    pub opt_not_domains: Option<Arc<[Hash]>>,
+    pub redirect: Option<Arc<str>>,
+    pub hostname: Option<Arc<str>>,
+    pub csp: Option<Arc<str>>,
     pub bug: Option<u32>,
-    pub tag: Option<String>,
+    pub tag: Option<Arc<str>>,
 
     pub raw_line: Option<String>,
 
@@ -194,6 +220,15 @@ pub struct NetworkFilter {
     pub opt_domains_union: Option<Hash>,
     pub opt_not_domains_union: Option<Hash>,
 
+    /// Value of the `$domain=` option as a sorted list, e.g. `a.com|~b.com`,
+    /// as only hashes of the domains are used for matching
+    #[serde(default)]
+    pub opt_domains_text: Option<Arc<str>>,
+    /// Identifier of the list the filter comes from, e.g. `FilterList::uuid`, see
+    /// `lists::parse_filters_for_list`
+    #[serde(default)]
+    pub list_id: Option<Arc<str>>,
+
     // Regex compild lazily, using "Interior Mutability"
     // Arc (Atomic Reference Counter) allows for cloned NetworkFilters
     // to point to the same RwLock and what is inside.
@@ -226,6 +261,8 @@ impl NetworkFilter {
         let mut opt_domains_union: Option<Hash> = None;
         let mut opt_not_domains_union: Option<Hash> = None;
 
+        let mut opt_domains_text: Option<String> = None;
+
         let mut redirect: Option<String> = None;
         let mut csp: Option<String> = None;
         let mut bug: Option<u32> = None;
@@ -274,6 +311,7 @@ impl NetworkFilter {
                         // Benchmarking doesn't indicate signficant performance degradation across the entire easylist
                         option_values.sort();
                         option_values.dedup();
+                        opt_domains_text = Some(option_values.join("|"));
                         let mut opt_domains_array: Vec<Hash> = vec![];
                         let mut opt_not_domains_array: Vec<Hash> = vec![];
 
@@ -343,22 +381,7 @@ impl NetworkFilter {
                     }
                     (_, negation) => {
                         // Handle content type options separatly
-                        let mut option_mask = NetworkFilterMask::NONE;
-                        match option {
-                            "image" => option_mask.set(NetworkFilterMask::FROM_IMAGE, true),
-                            "media" => option_mask.set(NetworkFilterMask::FROM_MEDIA, true),
-                            "object" | "object-subrequest" => option_mask.set(NetworkFilterMask::FROM_OBJECT, true),
-                            "other" => option_mask.set(NetworkFilterMask::FROM_OTHER, true),
-                            "ping" | "beacon" => option_mask.set(NetworkFilterMask::FROM_PING, true),
-                            "script" => option_mask.set(NetworkFilterMask::FROM_SCRIPT, true),
-                            "css" | "stylesheet" => option_mask.set(NetworkFilterMask::FROM_STYLESHEET, true),
-                            "frame" | "subdocument" => option_mask.set(NetworkFilterMask::FROM_SUBDOCUMENT, true),
-                            "main_frame" | "document" => option_mask.set(NetworkFilterMask::FROM_DOCUMENT, true),
-                            "xhr" | "xmlhttprequest" => option_mask.set(NetworkFilterMask::FROM_XMLHTTPREQUEST, true),
-                            "websocket" => option_mask.set(NetworkFilterMask::FROM_WEBSOCKET, true),
-                            "font" => option_mask.set(NetworkFilterMask::FROM_FONT, true),
-                            _ => return Err(FilterError::UnrecognisedOption),
-                        }
+                        let option_mask = content_type_mask(option).ok_or(FilterError::UnrecognisedOption)?;
 
                         // We got a valid cpt option, update mask
                         if negation {
@@ -525,7 +548,12 @@ impl NetworkFilter {
                 NetworkFilterMask::IS_REGEX,
                 check_is_regex(&line[filter_index_start..filter_index_end]),
             );
-            Some(String::from(&line[filter_index_start..filter_index_end]).to_lowercase())
+            let pattern = String::from(&line[filter_index_start..filter_index_end]);
+            if mask.contains(NetworkFilterMask::MATCH_CASE) {
+                Some(pattern)
+            } else {
+                Some(pattern.to_lowercase())
+            }
         } else {
             None
         };
@@ -565,23 +593,25 @@ impl NetworkFilter {
 
         Ok(NetworkFilter {
             bug,
-            csp,
+            csp: csp.map(Arc::from),
             filter: if let Some(simple_filter) = filter {
                 FilterPart::Simple(simple_filter)
             } else {
                 FilterPart::Empty
             },
-            hostname: hostname_decoded.map_or(Ok(None), |r| r.map(Some))?,
+            hostname: hostname_decoded.map_or(Ok(None), |r| r.map(|hostname| Some(Arc::from(hostname))))?,
             mask,
-            opt_domains,
-            opt_not_domains,
-            tag,
+            opt_domains: opt_domains.map(Arc::from),
+            opt_not_domains: opt_not_domains.map(Arc::from),
+            tag: tag.map(Arc::from),
+            opt_domains_text: opt_domains_text.map(Arc::from),
+            list_id: None,
             raw_line: if debug {
                 Some(String::from(line))
             } else {
                 None
             },
-            redirect,
+            redirect: redirect.map(Arc::from),
             id: utils::fast_hash(&line),
             fuzzy_signature: maybe_fuzzy_signature,
             opt_domains_union,
@@ -590,34 +620,164 @@ impl NetworkFilter {
         })
     }
 
+    /// The rule the filter was parsed from if it was kept, in debug mode, or else `to_rule`.
     pub fn to_string(&self) -> String {
         match self.raw_line.as_ref() {
             Some(r) => r.clone(),
-            None => String::from(""),
+            None => self.to_rule(),
+        }
+    }
+
+    /**
+     * Prints the filter as a rule, in a canonical form: parsing it gives back an
+     * equivalent filter, e.g. `||example.com^$script,third-party` for
+     * `||example.com^$third-party,script`. A filter fused from several
+     * rules by the optimizer prints one rule per pattern, separated by ` <+> `,
+     * the same way the optimizer joins their raw lines. Such text is meant to be
+     * read, it does not parse back to the fused filter, nor to any of its rules.
+     */
+    pub fn to_rule(&self) -> String {
+        match &self.filter {
+            FilterPart::AnyOf(patterns) => patterns
+                .iter()
+                .map(|pattern| self.to_rule_with_pattern(pattern))
+                .collect::<Vec<_>>()
+                .join(" <+> "),
+            FilterPart::Simple(pattern) => self.to_rule_with_pattern(pattern),
+            FilterPart::Empty => self.to_rule_with_pattern(""),
         }
     }
 
+    fn to_rule_with_pattern(&self, pattern: &str) -> String {
+        let mut rule = String::new();
+        if self.is_exception() {
+            rule.push_str("@@");
+        }
+
+        if let Some(hostname) = self.hostname.as_deref() {
+            rule.push_str("||");
+            rule.push_str(hostname);
+            if pattern.is_empty() {
+                if self.is_right_anchor() {
+                    rule.push('^');
+                }
+            } else {
+                if !self.is_left_anchor() {
+                    rule.push('*');
+                }
+                rule.push_str(pattern);
+                if self.is_right_anchor() {
+                    rule.push('|');
+                }
+            }
+        } else if pattern.is_empty() {
+            rule.push_str(match (self.for_http(), self.for_https()) {
+                (true, false) => "|http://",
+                (false, true) => "|https://",
+                _ => "*",
+            });
+        } else {
+            if self.is_left_anchor() {
+                rule.push('|');
+            } else if pattern.starts_with(|c| matches!(c, '|' | '@' | '!' | '[' | '#' | '*')) {
+                // keep the start of the pattern from reading as an anchor, exception or comment
+                rule.push('*');
+            }
+            rule.push_str(pattern);
+            if self.is_right_anchor() {
+                rule.push('|');
+            } else if pattern.ends_with('|') {
+                rule.push('*');
+            }
+        }
+
+        let options = self.options();
+        if !options.is_empty() || rule.contains('$') {
+            rule.push('$');
+            rule.push_str(&options.join(","));
+        }
+        rule
+    }
+
+    /// Options of the rule printed by `to_rule`.
+    fn options(&self) -> Vec<String> {
+        let mut options: Vec<String> = Vec::new();
+
+        let content_types = self.mask & (NetworkFilterMask::FROM_ANY | NetworkFilterMask::FROM_DOCUMENT);
+        let included = CONTENT_TYPE_OPTIONS.iter().filter(|(_, mask)| content_types.contains(*mask));
+        let excluded = CONTENT_TYPE_OPTIONS
+            .iter()
+            .filter(|(_, mask)| NetworkFilterMask::FROM_ANY.contains(*mask) && !content_types.contains(*mask));
+        if content_types.is_empty() {
+            options.push(String::from("image"));
+            options.push(String::from("~image"));
+        } else if content_types != NetworkFilterMask::FROM_ANY {
+            if content_types.contains(NetworkFilterMask::FROM_DOCUMENT) || included.clone().count() <= excluded.clone().count() {
+                options.extend(included.map(|(name, _)| String::from(*name)));
+            } else {
+                options.extend(excluded.map(|(name, _)| format!("~{}", name)));
+            }
+        }
+
+        if !self.third_party() {
+            options.push(String::from("first-party"));
+        }
+        if !self.first_party() {
+            options.push(String::from("third-party"));
+        }
+        for (flag, name) in [
+            (NetworkFilterMask::IS_IMPORTANT, "important"),
+            (NetworkFilterMask::MATCH_CASE, "match-case"),
+            (NetworkFilterMask::FUZZY_MATCH, "fuzzy"),
+            (NetworkFilterMask::EXPLICIT_CANCEL, "explicitcancel"),
+            (NetworkFilterMask::BAD_FILTER, "badfilter"),
+        ].iter() {
+            if self.mask.contains(*flag) {
+                options.push(String::from(*name));
+            }
+        }
+        if let Some(domains) = self.opt_domains_text.as_deref() {
+            options.push(format!("domain={}", domains));
+        }
+        if self.is_csp() {
+            options.push(match self.csp.as_deref() {
+                Some(csp) => format!("csp={}", csp),
+                None => String::from("csp"),
+            });
+        }
+        if let Some(redirect) = self.redirect.as_deref() {
+            options.push(format!("redirect={}", redirect));
+        }
+        if let Some(tag) = self.tag.as_deref() {
+            options.push(format!("tag={}", tag));
+        }
+        if let Some(bug) = self.bug {
+            options.push(format!("bug={}", bug));
+        }
+        options
+    }
+
     pub fn get_id_without_badfilter(&self) -> Hash {
         let mut mask = self.mask;
         mask.set(NetworkFilterMask::BAD_FILTER, false);
         compute_filter_id(
-            self.csp.as_ref().map(String::as_str),
+            self.csp.as_deref(),
             mask,
             self.filter.string_view().as_ref().map(|s| s.as_str()),
-            self.hostname.as_ref().map(String::as_str),
-            self.opt_domains.as_ref(),
-            self.opt_not_domains.as_ref(),
+            self.hostname.as_deref(),
+            self.opt_domains.as_deref(),
+            self.opt_not_domains.as_deref(),
         )
     }
 
     pub fn get_id(&self) -> Hash {
         compute_filter_id(
-            self.csp.as_ref().map(String::as_str),
+            self.csp.as_deref(),
             self.mask,
             self.filter.string_view().as_ref().map(|s| s.as_str()),
-            self.hostname.as_ref().map(String::as_str),
-            self.opt_domains.as_ref(),
-            self.opt_not_domains.as_ref(),
+            self.hostname.as_deref(),
+            self.opt_domains.as_deref(),
+            self.opt_not_domains.as_deref(),
         )
     }
 
@@ -659,6 +819,14 @@ impl NetworkFilter {
         match &self.filter {
             FilterPart::Simple(f) => {
                 if !self.is_complete_regex() {
+                    // Requests are tokenized in lowercase
+                    let lowercase;
+                    let f = if self.match_case() {
+                        lowercase = f.to_lowercase();
+                        &lowercase
+                    } else {
+                        f
+                    };
                     let skip_last_token =
                         (self.is_plain() || self.is_regex()) && !self.is_right_anchor() && !self.is_fuzzy();
                     let skip_first_token = self.is_right_anchor();
@@ -683,8 +851,8 @@ impl NetworkFilter {
         // this filter in multiple buckets based on the domains option.
         if tokens.is_empty() && self.opt_domains.is_some() && self.opt_not_domains.is_none() {
             self.opt_domains
-                .as_ref()
-                .unwrap_or(&vec![])
+                .as_deref()
+                .unwrap_or(&[])
                 .iter()
                 .map(|&d| vec![d])
                 .collect()
@@ -720,6 +888,43 @@ impl NetworkFilter {
     pub fn is_right_anchor(&self) -> bool {
         self.mask.contains(NetworkFilterMask::IS_RIGHT_ANCHOR)
     }
+
+    /// Pattern of filters matched by looking for it anywhere in the URL, if any.
+    pub fn plain_pattern(&self) -> Option<&str> {
+        if self.is_hostname_anchor()
+            || self.is_regex()
+            || self.is_complete_regex()
+            || self.is_left_anchor()
+            || self.is_right_anchor()
+            || self.is_fuzzy()
+            || self.match_case()
+        {
+            return None;
+        }
+        match &self.filter {
+            FilterPart::Simple(pattern) if !pattern.is_empty() => Some(pattern),
+            _ => None,
+        }
+    }
+
+    /// Checks everything but the pattern, for callers that already know the pattern matches.
+    pub(crate) fn matches_options(&self, request: &request::Request) -> bool {
+        check_options(self, request)
+    }
+
+    /// `||hostname^` filters, matching requests to `hostname` or any of its
+    /// subdomains regardless of the rest of the URL.
+    pub fn is_hostname_only(&self) -> bool {
+        self.is_hostname_anchor()
+            && self.is_right_anchor()
+            && !self.is_left_anchor()
+            && !self.is_regex()
+            && !self.is_complete_regex()
+            && !self.is_fuzzy()
+            && !self.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)
+            && matches!(self.filter, FilterPart::Empty)
+            && self.hostname.as_ref().map(|h| !h.is_empty() && !h.starts_with('.') && !h.ends_with('.')).unwrap_or(false)
+    }
     
     pub fn is_left_anchor(&self) -> bool {
         self.mask.contains(NetworkFilterMask::IS_LEFT_ANCHOR)
@@ -752,6 +957,11 @@ impl NetworkFilter {
     pub fn is_complete_regex(&self) -> bool {
         self.mask.contains(NetworkFilterMask::IS_COMPLETE_REGEX)
     }
+
+    /// The regex compiled by an earlier `get_regex` call, if any, without compiling it.
+    pub fn compiled_regex(&self) -> Option<Rc<CompiledRegex>> {
+        self.regex.borrow().clone()
+    }
     
     fn is_plain(&self) -> bool {
         !self.is_regex()
@@ -799,6 +1009,14 @@ impl NetworkMatchable for NetworkFilter {
 
     // Lazily get the regex if the filter has one
     fn get_regex(&self) -> Rc<CompiledRegex> {
+        self.get_regex_with_limits(&RegexLimits::default())
+    }
+}
+
+impl NetworkFilter {
+    /// Same as `NetworkMatchable::get_regex`, but a regex that is not compiled
+    /// yet is compiled within `limits`. Once compiled, it is kept as it is.
+    pub fn get_regex_with_limits(&self, limits: &RegexLimits) -> Rc<CompiledRegex> {
         if !self.is_regex() && !self.is_complete_regex() {
             return Rc::new(CompiledRegex::MatchAll);
         }
@@ -815,6 +1033,7 @@ impl NetworkMatchable for NetworkFilter {
                 self.is_right_anchor(),
                 self.is_left_anchor(),
                 self.is_complete_regex(),
+                limits,
             );
 
             *cache = Some(Rc::new(regex));
@@ -824,10 +1043,62 @@ impl NetworkMatchable for NetworkFilter {
         // of the cache fall out of scope then the subsequent
         // recursive borrow would cause a dynamic thread panic.
         // This is the major hazard of using `RefCell`.
-        self.get_regex()
+        self.get_regex_with_limits(limits)
     }
 }
 
+/// Options other than content types, see `content_type_mask`.
+const NON_CONTENT_TYPE_OPTIONS: &[&str] = &[
+    "domain", "badfilter", "important", "match-case", "third-party", "3p", "first-party", "1p",
+    "fuzzy", "collapse", "bug", "tag", "redirect", "explicitcancel", "csp",
+];
+
+/// Content type options printed by `NetworkFilter::to_rule`, one name for each type.
+const CONTENT_TYPE_OPTIONS: &[(&str, NetworkFilterMask)] = &[
+    ("document", NetworkFilterMask::FROM_DOCUMENT),
+    ("font", NetworkFilterMask::FROM_FONT),
+    ("image", NetworkFilterMask::FROM_IMAGE),
+    ("media", NetworkFilterMask::FROM_MEDIA),
+    ("object", NetworkFilterMask::FROM_OBJECT),
+    ("other", NetworkFilterMask::FROM_OTHER),
+    ("ping", NetworkFilterMask::FROM_PING),
+    ("script", NetworkFilterMask::FROM_SCRIPT),
+    ("stylesheet", NetworkFilterMask::FROM_STYLESHEET),
+    ("subdocument", NetworkFilterMask::FROM_SUBDOCUMENT),
+    ("websocket", NetworkFilterMask::FROM_WEBSOCKET),
+    ("xmlhttprequest", NetworkFilterMask::FROM_XMLHTTPREQUEST),
+];
+
+/// Mask of the requests a content type option such as `script` applies to.
+fn content_type_mask(option: &str) -> Option<NetworkFilterMask> {
+    let mask = match option {
+        "image" => NetworkFilterMask::FROM_IMAGE,
+        "media" => NetworkFilterMask::FROM_MEDIA,
+        "object" | "object-subrequest" => NetworkFilterMask::FROM_OBJECT,
+        "other" => NetworkFilterMask::FROM_OTHER,
+        "ping" | "beacon" => NetworkFilterMask::FROM_PING,
+        "script" => NetworkFilterMask::FROM_SCRIPT,
+        "css" | "stylesheet" => NetworkFilterMask::FROM_STYLESHEET,
+        "frame" | "subdocument" => NetworkFilterMask::FROM_SUBDOCUMENT,
+        "main_frame" | "document" => NetworkFilterMask::FROM_DOCUMENT,
+        "xhr" | "xmlhttprequest" => NetworkFilterMask::FROM_XMLHTTPREQUEST,
+        "websocket" => NetworkFilterMask::FROM_WEBSOCKET,
+        "font" => NetworkFilterMask::FROM_FONT,
+        _ => return None,
+    };
+    Some(mask)
+}
+
+/// The first option of a network filter `line` that `NetworkFilter::parse`
+/// does not know, e.g. `popup` for `/ads/$popup,third-party`.
+pub fn unrecognised_option(line: &str) -> Option<&str> {
+    let options_index = twoway::rfind_str(line, "$")?;
+    line[options_index + 1..]
+        .split(',')
+        .map(|raw_option| raw_option.trim_start_matches('~').split('=').next().unwrap_or_default())
+        .find(|option| !NON_CONTENT_TYPE_OPTIONS.contains(option) && content_type_mask(option).is_none())
+}
+
 // ---------------------------------------------------------------------------
 // Filter parsing
 // ---------------------------------------------------------------------------
@@ -837,8 +1108,8 @@ fn compute_filter_id(
     mask: NetworkFilterMask,
     filter: Option<&str>,
     hostname: Option<&str>,
-    opt_domains: Option<&Vec<Hash>>,
-    opt_not_domains: Option<&Vec<Hash>>,
+    opt_domains: Option<&[Hash]>,
+    opt_not_domains: Option<&[Hash]>,
 ) -> Hash {
     let mut hash: Hash = (5408 * 33) ^ Hash::from(mask.bits);
 
@@ -881,67 +1152,51 @@ fn compute_filter_id(
 /**
  * Compiles a filter pattern to a regex. This is only performed *lazily* for
  * filters containing at least a * or ^ symbol. Because Regexes are expansive,
- * we try to convert some patterns to plain filters.
+ * patterns that only use * and ^ are matched as `WildcardPattern`s instead, and
+ * only complete regex filters (/.../) go through the regex engine. Those that
+ * would exceed `limits` once compiled result in a `RegexParsingError`.
  */
-#[allow(clippy::trivial_regex)]
 pub fn compile_regex(
     filter: &FilterPart,
     is_right_anchor: bool,
     is_left_anchor: bool,
     is_complete_regex: bool,
+    limits: &RegexLimits,
 ) -> CompiledRegex {
-    lazy_static! {
-      // Escape special regex characters: |.$+?{}()[]\
-      static ref SPECIAL_RE: Regex = Regex::new(r"([\|\.\$\+\?\{\}\(\)\[\]])").unwrap();
-      // * can match anything
-      static ref WILDCARD_RE: Regex = Regex::new(r"\*").unwrap();
-      // ^ can match any separator or the end of the pattern
-      static ref ANCHOR_RE: Regex = Regex::new(r"\^(.)").unwrap();
-      // ^ can match any separator or the end of the pattern
-      static ref ANCHOR_RE_EOL: Regex = Regex::new(r"\^$").unwrap();
-    }
-
     let filters: Vec<String> = match filter {
         FilterPart::Empty => vec![],
         FilterPart::Simple(s) => vec![s.clone()],
         FilterPart::AnyOf(f) => f.clone(),
     };
 
-    let mut escaped_patterns = Vec::with_capacity(filters.len());
-    for filter_str in filters {
-        // If any filter is empty, the entire set matches anything
-        if filter_str.is_empty() {
-            return CompiledRegex::MatchAll;
-        }
-        if is_complete_regex {
-            // unescape unrecognised escaping sequences, otherwise a normal regex
-            let unescaped = filter_str[1..filter_str.len() - 1]
-                .replace("\\/", "/")
-                .replace("\\:", ":");
-
-            escaped_patterns.push(unescaped);
-        } else {
-            let repl = SPECIAL_RE.replace_all(&filter_str, "\\$1");
-            let repl = WILDCARD_RE.replace_all(&repl, ".*");
-            // in adblock rules, '^' is a separator.
-            // The separator character is anything but a letter, a digit, or one of the following: _ - . %
-            let repl = ANCHOR_RE.replace_all(&repl, "(?:[^\\w\\d\\._%-])$1");
-            let repl = ANCHOR_RE_EOL.replace_all(&repl, "(?:[^\\w\\d\\._%-]|$)");
-
-            // Should match start or end of url
-            let left_anchor = if is_left_anchor { "^" } else { "" };
-            let right_anchor = if is_right_anchor { "$" } else { "" };
-            let filter = format!("{}{}{}", left_anchor, repl, right_anchor);
+    // If any filter is empty, the entire set matches anything
+    if filters.is_empty() || filters.iter().any(|f| f.is_empty()) {
+        return CompiledRegex::MatchAll;
+    }
 
-            escaped_patterns.push(filter);
-        }
+    if !is_complete_regex {
+        let patterns = filters
+            .iter()
+            .map(|f| WildcardPattern::new(f, is_left_anchor, is_right_anchor))
+            .collect();
+        return CompiledRegex::Wildcard(patterns);
     }
 
-    if escaped_patterns.is_empty() {
-        CompiledRegex::MatchAll
-    } else if escaped_patterns.len() == 1 {
+    // unescape unrecognised escaping sequences, otherwise a normal regex
+    let escaped_patterns: Vec<String> = filters
+        .iter()
+        .map(|filter_str| filter_str[1..filter_str.len() - 1]
+            .replace("\\/", "/")
+            .replace("\\:", ":"))
+        .collect();
+
+    if escaped_patterns.len() == 1 {
         let pattern = &escaped_patterns[0];
-        match Regex::new(&pattern) {
+        let compiled = RegexBuilder::new(&pattern)
+            .size_limit(limits.size_limit)
+            .dfa_size_limit(limits.dfa_size_limit)
+            .build();
+        match compiled {
             Ok(compiled) => CompiledRegex::Compiled(compiled),
             Err(e) => {
                 // println!("Regex parsing failed ({:?})", e);
@@ -949,7 +1204,11 @@ pub fn compile_regex(
             }
         }
     } else {
-        match RegexSet::new(escaped_patterns) {
+        let compiled = RegexSetBuilder::new(escaped_patterns)
+            .size_limit(limits.size_limit)
+            .dfa_size_limit(limits.dfa_size_limit)
+            .build();
+        match compiled {
             Ok(compiled) => CompiledRegex::CompiledSet(compiled),
             Err(e) => CompiledRegex::RegexParsingError(e),
         }
@@ -1139,7 +1398,7 @@ fn check_pattern_hostname_anchor_regex_filter(
 ) -> bool {
     filter
         .hostname
-        .as_ref()
+        .as_deref()
         .map(|hostname| {
             if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 check_pattern_regex_filter_at(
@@ -1161,7 +1420,7 @@ fn check_pattern_hostname_right_anchor_filter(
 ) -> bool {
     filter
         .hostname
-        .as_ref()
+        .as_deref()
         .map(|hostname| {
             if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 match &filter.filter {
@@ -1193,7 +1452,7 @@ fn check_pattern_hostname_left_right_anchor_filter(
 
     filter
         .hostname
-        .as_ref()
+        .as_deref()
         .map(|hostname| {
             if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 match &filter.filter {
@@ -1228,7 +1487,7 @@ fn check_pattern_hostname_left_anchor_filter(
 ) -> bool {
     filter
         .hostname
-        .as_ref()
+        .as_deref()
         .map(|hostname| {
             if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 match &filter.filter {
@@ -1264,7 +1523,7 @@ fn check_pattern_hostname_anchor_filter(
 ) -> bool {
     filter
         .hostname
-        .as_ref()
+        .as_deref()
         .map(|hostname| {
             if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 match &filter.filter {
@@ -1299,7 +1558,7 @@ fn check_pattern_hostname_anchor_fuzzy_filter(
 ) -> bool {
     filter
         .hostname
-        .as_ref()
+        .as_deref()
         .map(|hostname| {
             if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 check_pattern_fuzzy_filter(filter, request)
@@ -1315,6 +1574,15 @@ fn check_pattern_hostname_anchor_fuzzy_filter(
  * efficient matching function.
  */
 fn check_pattern(filter: &NetworkFilter, request: &request::Request) -> bool {
+    if filter.match_case() {
+        if let Some(request) = request.with_original_case() {
+            return check_pattern_with_case(filter, &request);
+        }
+    }
+    check_pattern_with_case(filter, request)
+}
+
+fn check_pattern_with_case(filter: &NetworkFilter, request: &request::Request) -> bool {
     if filter.is_hostname_anchor() {
         if filter.is_regex() {
             check_pattern_hostname_anchor_regex_filter(filter, request)
@@ -1384,7 +1652,7 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
                     return false
                 }
             }
-            if source_hashes.iter().all(|h| !utils::bin_lookup(&included_domains, *h)) {
+            if source_hashes.iter().all(|h| !utils::bin_lookup(included_domains, *h)) {
                 return false
             }
         }
@@ -1395,10 +1663,10 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
             // If the union of excluded domains is recorded
             if let Some(excluded_domains_union) = filter.opt_not_domains_union {
                 // If there's any source hash that matches the union, check the actual values
-                if source_hashes.iter().any(|h| (h & excluded_domains_union == *h) && utils::bin_lookup(&excluded_domains, *h)) {
+                if source_hashes.iter().any(|h| (h & excluded_domains_union == *h) && utils::bin_lookup(excluded_domains, *h)) {
                     return false
                 }
-            } else if source_hashes.iter().any(|h| utils::bin_lookup(&excluded_domains, *h)) {
+            } else if source_hashes.iter().any(|h| utils::bin_lookup(excluded_domains, *h)) {
                 return false
             }
         }
@@ -1456,11 +1724,11 @@ mod parse_tests {
             NetworkFilterBreakdown {
                 filter: filter.filter.string_view(),
                 bug: filter.bug.as_ref().cloned(),
-                csp: filter.csp.as_ref().cloned(),
-                hostname: filter.hostname.as_ref().cloned(),
-                opt_domains: filter.opt_domains.as_ref().cloned(),
-                opt_not_domains: filter.opt_not_domains.as_ref().cloned(),
-                redirect: filter.redirect.as_ref().cloned(),
+                csp: filter.csp.as_deref().map(String::from),
+                hostname: filter.hostname.as_deref().map(String::from),
+                opt_domains: filter.opt_domains.as_deref().map(<[Hash]>::to_vec),
+                opt_not_domains: filter.opt_not_domains.as_deref().map(<[Hash]>::to_vec),
+                redirect: filter.redirect.as_deref().map(String::from),
 
                 // filter type
                 is_fuzzy: filter.is_fuzzy(),
@@ -1535,6 +1803,21 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn detects_hostname_only_filters() {
+        let is_hostname_only = |filter: &str| NetworkFilter::parse(filter, true).unwrap().is_hostname_only();
+        assert!(is_hostname_only("||foo.com^"));
+        assert!(is_hostname_only("||foo.com^$script,third-party"));
+        assert!(is_hostname_only("@@||foo.com^"));
+        assert!(is_hostname_only("||www.foo.com^"));
+        assert!(!is_hostname_only("||foo.com"));
+        assert!(!is_hostname_only("||foo.com/ads^"));
+        assert!(!is_hostname_only("||foo.*.com^"));
+        assert!(!is_hostname_only("||foo.com^ads"));
+        assert!(!is_hostname_only("|foo.com^"));
+        assert!(!is_hostname_only("foo.com^"));
+    }
+
     #[test]
     // pattern
     fn parses_plain_pattern() {
@@ -1981,7 +2264,7 @@ mod parse_tests {
             // parses simple CSP
             let filter = NetworkFilter::parse(r#"||foo.com$csp=self bar """#, true).unwrap();
             assert_eq!(filter.is_csp(), true);
-            assert_eq!(filter.csp, Some(String::from(r#"self bar """#)));
+            assert_eq!(filter.csp.as_deref(), Some(r#"self bar """#));
         }
         {
             // parses empty CSP
@@ -1996,7 +2279,7 @@ mod parse_tests {
                     .unwrap();
             assert_eq!(filter.is_csp(), true);
             assert_eq!(filter.mask.contains(NetworkFilterMask::FROM_IMAGE), true);
-            assert_eq!(filter.csp, Some(String::from(r#"self bar """#)));
+            assert_eq!(filter.csp.as_deref(), Some(r#"self bar """#));
         }
     }
 
@@ -2005,14 +2288,14 @@ mod parse_tests {
         // parses domain
         {
             let filter = NetworkFilter::parse("||foo.com$domain=bar.com", true).unwrap();
-            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("bar.com")]));
+            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("bar.com")].into()));
             assert_eq!(filter.opt_not_domains, None);
         }
         {
             let filter = NetworkFilter::parse("||foo.com$domain=bar.com|baz.com", true).unwrap();
             let mut domains = vec![utils::fast_hash("bar.com"), utils::fast_hash("baz.com")];
             domains.sort_unstable();
-            assert_eq!(filter.opt_domains, Some(domains));
+            assert_eq!(filter.opt_domains, Some(domains.into()));
             assert_eq!(filter.opt_not_domains, None);
         }
 
@@ -2022,7 +2305,7 @@ mod parse_tests {
             assert_eq!(filter.opt_domains, None);
             assert_eq!(
                 filter.opt_not_domains,
-                Some(vec![utils::fast_hash("bar.com")])
+                Some(vec![utils::fast_hash("bar.com")].into())
             );
         }
         {
@@ -2030,31 +2313,31 @@ mod parse_tests {
             assert_eq!(filter.opt_domains, None);
             let mut domains = vec![utils::fast_hash("bar.com"), utils::fast_hash("baz.com")];
             domains.sort_unstable();
-            assert_eq!(filter.opt_not_domains, Some(domains));
+            assert_eq!(filter.opt_not_domains, Some(domains.into()));
         }
         // parses domain and ~domain
         {
             let filter = NetworkFilter::parse("||foo.com$domain=~bar.com|baz.com", true).unwrap();
-            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("baz.com")]));
+            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("baz.com")].into()));
             assert_eq!(
                 filter.opt_not_domains,
-                Some(vec![utils::fast_hash("bar.com")])
+                Some(vec![utils::fast_hash("bar.com")].into())
             );
         }
         {
             let filter = NetworkFilter::parse("||foo.com$domain=bar.com|~baz.com", true).unwrap();
-            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("bar.com")]));
+            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("bar.com")].into()));
             assert_eq!(
                 filter.opt_not_domains,
-                Some(vec![utils::fast_hash("baz.com")])
+                Some(vec![utils::fast_hash("baz.com")].into())
             );
         }
         {
             let filter = NetworkFilter::parse("||foo.com$domain=foo|~bar|baz", true).unwrap();
             let mut domains = vec![utils::fast_hash("foo"), utils::fast_hash("baz")];
             domains.sort();
-            assert_eq!(filter.opt_domains, Some(domains));
-            assert_eq!(filter.opt_not_domains, Some(vec![utils::fast_hash("bar")]));
+            assert_eq!(filter.opt_domains, Some(domains.into()));
+            assert_eq!(filter.opt_not_domains, Some(vec![utils::fast_hash("bar")].into()));
         }
         // defaults to no constraint
         {
@@ -2069,11 +2352,11 @@ mod parse_tests {
         // parses redirect
         {
             let filter = NetworkFilter::parse("||foo.com$redirect=bar.js", true).unwrap();
-            assert_eq!(filter.redirect, Some(String::from("bar.js")));
+            assert_eq!(filter.redirect.as_deref(), Some("bar.js"));
         }
         {
             let filter = NetworkFilter::parse("$redirect=bar.js", true).unwrap();
-            assert_eq!(filter.redirect, Some(String::from("bar.js")));
+            assert_eq!(filter.redirect.as_deref(), Some("bar.js"));
         }
         // parses ~redirect
         {
@@ -2098,6 +2381,15 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn finds_unrecognised_option() {
+        assert_eq!(unrecognised_option("/ads/$popup,third-party"), Some("popup"));
+        assert_eq!(unrecognised_option("/ads/$script,~third-party,generichide"), Some("generichide"));
+        assert_eq!(unrecognised_option("/ads/$~image,domain=a.com|~b.com,redirect=noopjs"), None);
+        assert_eq!(unrecognised_option("/ads/"), None);
+        assert_eq!(NetworkFilter::parse("/ads/$popup,third-party", true).err(), Some(FilterError::UnrecognisedOption));
+    }
+
     #[test]
     fn parses_match_case() {
         // parses match-case
@@ -2126,6 +2418,19 @@ mod parse_tests {
             let filter = NetworkFilter::parse("||foo.com", true).unwrap();
             assert_eq!(filter.match_case(), false)
         }
+
+        // keeps the case of the pattern, but not of the hostname or its tokens
+        {
+            let filter = NetworkFilter::parse("||Foo.com/AdBlock.js$match-case", true).unwrap();
+            assert_eq!(filter.hostname.as_deref(), Some("foo.com"));
+            assert_eq!(filter.filter.string_view(), Some(String::from("/AdBlock.js")));
+            let lowercase = NetworkFilter::parse("||foo.com/adblock.js", true).unwrap();
+            assert_eq!(filter.get_tokens(), lowercase.get_tokens());
+        }
+        {
+            let filter = NetworkFilter::parse("/AdBlock.js", true).unwrap();
+            assert_eq!(filter.filter.string_view(), Some(String::from("/adblock.js")));
+        }
     }
 
     #[test]
@@ -2464,8 +2769,68 @@ mod parse_tests {
         assert_eq!(defaults, NetworkFilterBreakdown::from(&filter));
     }
 
+    #[test]
+    fn prints_canonical_rules() {
+        let canonical = |rule: &str| NetworkFilter::parse(rule, false).unwrap().to_rule();
+        assert_eq!(canonical("||www.example.com^$third-party,script"), "||example.com^$script,third-party");
+        assert_eq!(canonical("@@||example.com/ads/*.js|$domain=b.com|~a.com|b.com,important"), "@@||example.com/ads/*.js|$important,domain=b.com|~a.com");
+        assert_eq!(canonical("/banner/ads$~image,~script,~media,~font"), "/banner/ads$~font,~image,~media,~script");
+        assert_eq!(canonical("|https://$image,1p"), "|https://$image,first-party");
+        assert_eq!(canonical("$csp=script-src 'self',tag=social"), "*$csp=script-src 'self',tag=social");
+        assert_eq!(canonical("||example.com^$redirect=noop.js,script,bug=123"), "||example.com^$script,redirect=noop.js,bug=123");
+    }
+
+    #[test]
+    fn canonical_rules_parse_to_equal_filters() {
+        let rules = [
+            "||foo.com",
+            "||foo.com^",
+            "||foo.com/bar/baz$important",
+            "||foo.com*bar^|",
+            "@@||$domain=auth.wi-fi.ru",
+            "|https://$image,third-party",
+            "|http://",
+            "*",
+            "/ads/banner.",
+            "|http://ads.com/",
+            "ads.js|",
+            "*/||ads",
+            "@@*@example.com",
+            r"/ads\d+\.js/$script,match-case",
+            "/ads$1p,~document,~xhr,~websocket",
+            "/ads$document,subdocument",
+            "/pixel$image,~image",
+            "||example.com^$domain=a.com|~b.com|c.com,redirect=noop.js,important",
+            "||example.com^$csp=script-src 'none',badfilter",
+            "||example.com^$csp",
+            "@@||example.com^$explicitcancel,bug=42,tag=social",
+            "ads$fuzzy",
+            "foo$bar$script",
+        ];
+        for rule in rules.iter() {
+            let filter = NetworkFilter::parse(rule, false).unwrap();
+            let canonical = filter.to_rule();
+            let reparsed = NetworkFilter::parse(&canonical, false)
+                .unwrap_or_else(|e| panic!("{} printed as {} does not parse: {:?}", rule, canonical, e));
+            assert_eq!(NetworkFilterBreakdown::from(&filter), NetworkFilterBreakdown::from(&reparsed), "{} printed as {}", rule, canonical);
+            assert_eq!(filter.mask, reparsed.mask, "{} printed as {}", rule, canonical);
+            assert_eq!(filter.get_id(), reparsed.get_id(), "{} printed as {}", rule, canonical);
+            assert_eq!(filter.tag, reparsed.tag, "{} printed as {}", rule, canonical);
+            assert_eq!(filter.opt_domains_text, reparsed.opt_domains_text, "{} printed as {}", rule, canonical);
+            assert_eq!(reparsed.to_rule(), canonical);
+        }
+    }
+
+    #[test]
+    fn to_string_falls_back_to_canonical_rule() {
+        let rule = "||foo.com^$third-party,script";
+        assert_eq!(NetworkFilter::parse(rule, true).unwrap().to_string(), rule);
+        assert_eq!(NetworkFilter::parse(rule, false).unwrap().to_string(), "||foo.com^$script,third-party");
+    }
+
 }
 
+
 #[cfg(test)]
 mod match_tests {
     use super::*;
@@ -2579,6 +2944,22 @@ mod match_tests {
         filter_match_url("https://bar.com/bar/baz", "https://bar.com/bar/baz", true);
     }
 
+    #[test]
+    // pattern$match-case
+    fn check_pattern_match_case_works() {
+        filter_match_url("/AdBlock.js$match-case", "https://foo.com/AdBlock.js", true);
+        filter_match_url("/AdBlock.js$match-case", "https://foo.com/adblock.js", false);
+        filter_match_url("/AdBlock.js", "https://foo.com/AdBlock.js", true);
+        filter_match_url("/AdBlock.js", "https://foo.com/adblock.js", true);
+        filter_match_url("|https://foo.com/AdBlock$match-case", "https://foo.com/AdBlock.js", true);
+        filter_match_url("||foo.com/AdBlock^$match-case", "https://www.foo.com/AdBlock?x=1", true);
+        filter_match_url("||foo.com/AdBlock^$match-case", "https://www.foo.com/ADBLOCK?x=1", false);
+        filter_match_url("/Ad*Block$match-case", "https://foo.com/Ad/Block", true);
+        filter_match_url("/Ad*Block$match-case", "https://foo.com/ad/block", false);
+        filter_match_url("/Ad[0-9]+Block/$match-case", "https://foo.com/Ad42Block", true);
+        filter_match_url("/Ad[0-9]+Block/$match-case", "https://foo.com/ad42block", false);
+    }
+
     #[test]
     // pattern$fuzzy
     fn check_pattern_fuzzy_filter_works() {
@@ -2963,6 +3344,21 @@ mod match_tests {
         }
     }
 
+    #[test]
+    fn compile_regex_respects_size_limit() {
+        let filter = FilterPart::Simple(String::from(r"/ads\d+\/banner[a-z]{2,}/"));
+        let limits = RegexLimits { size_limit: 10, dfa_size_limit: 10 };
+        let regex = compile_regex(&filter, false, false, true, &limits);
+        assert!(matches!(regex, CompiledRegex::RegexParsingError(regex::Error::CompiledTooBig(10))), "Expected size limit error, got {:?}", regex);
+
+        let regex = compile_regex(&filter, false, false, true, &RegexLimits::default());
+        assert!(regex.is_match("https://foo.com/ads12/bannerxl.gif"));
+
+        let filters = FilterPart::AnyOf(vec![String::from("/ads.*/"), String::from("/banner[0-9]/")]);
+        let regex = compile_regex(&filters, false, false, true, &limits);
+        assert!(matches!(regex, CompiledRegex::RegexParsingError(regex::Error::CompiledTooBig(10))), "Expected size limit error, got {:?}", regex);
+    }
+
     #[test]
     #[ignore] // Not going to handle lookaround regexes
     fn check_lookaround_regex_handled() {
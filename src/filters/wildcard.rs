@@ -0,0 +1,256 @@
+//! Matching of filter patterns containing `*` wildcards and `^` separators,
+//! without compiling them to a regex.
+
+#[derive(Debug, Clone, PartialEq)]
+enum PatternPart {
+    Literal(String),
+    /// `^` in the middle of a pattern, matching exactly one separator character
+    Separator,
+    /// `^` ending the pattern, matching a separator character or the end of the URL
+    SeparatorOrEnd,
+}
+
+/**
+ * A filter pattern split on `*` into segments of literals and separators.
+ * Every segment matches a fixed number of characters, so the pattern can be
+ * matched by finding each segment in turn, as early as possible.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct WildcardPattern {
+    segments: Vec<Vec<PatternPart>>,
+    left_anchor: bool,
+    right_anchor: bool,
+}
+
+/// In adblock rules, '^' is a separator.
+/// The separator character is anything but a letter, a digit, or one of the following: _ - . %
+pub fn is_separator(c: char) -> bool {
+    !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '%')
+}
+
+impl WildcardPattern {
+    pub fn new(pattern: &str, left_anchor: bool, right_anchor: bool) -> WildcardPattern {
+        let mut segments: Vec<Vec<PatternPart>> = pattern
+            .split('*')
+            .map(|segment| {
+                let mut parts = Vec::new();
+                let mut literal = String::new();
+                for c in segment.chars() {
+                    if c == '^' {
+                        if !literal.is_empty() {
+                            parts.push(PatternPart::Literal(std::mem::take(&mut literal)));
+                        }
+                        parts.push(PatternPart::Separator);
+                    } else {
+                        literal.push(c);
+                    }
+                }
+                if !literal.is_empty() {
+                    parts.push(PatternPart::Literal(literal));
+                }
+                parts
+            })
+            .collect();
+
+        // ^ can match any separator or the end of the pattern
+        if let Some(last) = segments.last_mut().and_then(|segment| segment.last_mut()) {
+            if *last == PatternPart::Separator {
+                *last = PatternPart::SeparatorOrEnd;
+            }
+        }
+
+        WildcardPattern {
+            segments,
+            left_anchor,
+            right_anchor,
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let last = self.segments.len() - 1;
+        let mut pos = 0;
+        for (i, segment) in self.segments.iter().enumerate() {
+            let anchored_end = i == last && self.right_anchor;
+            let found = if i == 0 && self.left_anchor {
+                match_at(segment, text, pos).filter(|&end| !anchored_end || end == text.len())
+            } else if anchored_end {
+                find_at_end(segment, text, pos)
+            } else {
+                find(segment, text, pos)
+            };
+
+            match found {
+                Some(end) => pos = end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Equivalent regex, as previously produced by `compile_regex`.
+    pub fn to_regex_string(&self) -> String {
+        let mut regex = String::new();
+        if self.left_anchor {
+            regex.push('^');
+        }
+        for (i, segment) in self.segments.iter().enumerate() {
+            if i > 0 {
+                regex.push_str(".*");
+            }
+            for part in segment {
+                match part {
+                    PatternPart::Literal(literal) => regex.push_str(&regex::escape(literal)),
+                    PatternPart::Separator => regex.push_str("(?:[^\\w\\d\\._%-])"),
+                    PatternPart::SeparatorOrEnd => regex.push_str("(?:[^\\w\\d\\._%-]|$)"),
+                }
+            }
+        }
+        if self.right_anchor {
+            regex.push('$');
+        }
+        regex
+    }
+}
+
+/// Matches `segment` starting exactly at `pos`, returning where the match ends.
+fn match_at(segment: &[PatternPart], text: &str, mut pos: usize) -> Option<usize> {
+    for part in segment {
+        match part {
+            PatternPart::Literal(literal) => {
+                if !text[pos..].starts_with(literal.as_str()) {
+                    return None;
+                }
+                pos += literal.len();
+            }
+            PatternPart::Separator => match text[pos..].chars().next() {
+                Some(c) if is_separator(c) => pos += c.len_utf8(),
+                _ => return None,
+            },
+            PatternPart::SeparatorOrEnd => match text[pos..].chars().next() {
+                None => {}
+                Some(c) if is_separator(c) => pos += c.len_utf8(),
+                _ => return None,
+            },
+        }
+    }
+    Some(pos)
+}
+
+/// Finds the first match of `segment` at or after `from`, returning where it ends.
+fn find(segment: &[PatternPart], text: &str, from: usize) -> Option<usize> {
+    match segment.first() {
+        None => Some(from),
+        // Only try positions where the leading literal occurs
+        Some(PatternPart::Literal(literal)) => text[from..]
+            .match_indices(literal.as_str())
+            .find_map(|(i, _)| match_at(segment, text, from + i)),
+        Some(_) => text[from..]
+            .char_indices()
+            .map(|(i, _)| from + i)
+            .chain(std::iter::once(text.len()))
+            .find_map(|start| match_at(segment, text, start)),
+    }
+}
+
+/// Finds a match of `segment` at or after `from` that ends with `text`.
+fn find_at_end(segment: &[PatternPart], text: &str, from: usize) -> Option<usize> {
+    let chars: usize = segment
+        .iter()
+        .map(|part| match part {
+            PatternPart::Literal(literal) => literal.chars().count(),
+            PatternPart::Separator => 1,
+            PatternPart::SeparatorOrEnd => 0,
+        })
+        .sum();
+    let may_end_with_separator = segment.last() == Some(&PatternPart::SeparatorOrEnd);
+
+    std::iter::once(chars)
+        .chain(if may_end_with_separator { Some(chars + 1) } else { None })
+        .filter_map(|n| start_of_last_chars(text, n))
+        .filter(|&start| start >= from)
+        .find(|&start| match_at(segment, text, start) == Some(text.len()))
+        .map(|_| text.len())
+}
+
+/// Byte offset at which the last `n` characters of `text` start.
+fn start_of_last_chars(text: &str, n: usize) -> Option<usize> {
+    if n == 0 {
+        Some(text.len())
+    } else {
+        text.char_indices().rev().nth(n - 1).map(|(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn check_same_as_regex(pattern: &str, left_anchor: bool, right_anchor: bool, texts: &[&str]) {
+        let wildcard = WildcardPattern::new(pattern, left_anchor, right_anchor);
+        let regex = Regex::new(&wildcard.to_regex_string()).unwrap();
+        for text in texts {
+            assert_eq!(
+                wildcard.is_match(text),
+                regex.is_match(text),
+                "Pattern {} (left anchor: {}, right anchor: {}) on {}",
+                pattern,
+                left_anchor,
+                right_anchor,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn matches_like_regex() {
+        let texts = [
+            "",
+            "/",
+            "https://foo.com/ads/banner.gif",
+            "https://foo.com/ads/banner",
+            "https://foo.com/ads/banner/",
+            "https://foo.com/ads-banner?x=1",
+            "https://foo.com/ads%20banner",
+            "https://foo.com/adsbanner/ads/banner",
+            "https://foo.com/ads/banner_ads/banner/",
+            "https://foo.com/annonce/reklam/ñ",
+            "https://foo.com/ads/banner/é",
+            "banner/ads",
+        ];
+        let patterns = [
+            "/ads/*",
+            "/ads/*banner",
+            "/ads^",
+            "/ads^banner^",
+            "banner^",
+            "*/banner",
+            "ads*banner*",
+            "^ads",
+            "**",
+            "https://foo.com/ads/*",
+            "/banner^*",
+            "/*/*/",
+            "^",
+            "ñ",
+            "/reklam/*^",
+        ];
+        for pattern in patterns.iter() {
+            for (left_anchor, right_anchor) in [(false, false), (true, false), (false, true), (true, true)].iter() {
+                check_same_as_regex(pattern, *left_anchor, *right_anchor, &texts);
+            }
+        }
+    }
+
+    #[test]
+    fn separator_matches_end_only_at_end_of_pattern() {
+        let wildcard = WildcardPattern::new("/ads^", false, false);
+        assert!(wildcard.is_match("https://foo.com/ads"));
+        assert!(wildcard.is_match("https://foo.com/ads?x"));
+        assert!(!wildcard.is_match("https://foo.com/ads.js"));
+
+        let wildcard = WildcardPattern::new("/ads^*", false, false);
+        assert!(!wildcard.is_match("https://foo.com/ads"));
+        assert!(wildcard.is_match("https://foo.com/ads/"));
+    }
+}
@@ -30,3 +30,8 @@ pub mod url_parser;
 pub mod engine;
 pub mod filter_lists;
 pub mod resources;
+pub mod observer;
+pub mod serialization;
+pub mod token_profile;
+pub mod compact;
+pub mod preprocessor;
@@ -1,8 +1,10 @@
-use crate::filters::network::NetworkFilter;
-use itertools::Either;
+use crate::filters::network::{unrecognised_option, FilterError as NetworkFilterError, NetworkFilter};
+use hashbrown::HashMap;
 use serde::{Serialize, Deserialize};
-
-use itertools::Itertools;
+use std::io::BufRead;
+use std::sync::Arc;
+use std::time::Duration;
+use regex::Regex;
 
 #[derive(Debug, PartialEq)]
 pub enum FilterType {
@@ -19,6 +21,56 @@ pub enum FilterError {
     ParseError,
 }
 
+/// Why `validate_checksum` rejected a list.
+#[derive(Debug, PartialEq)]
+pub enum ChecksumError {
+    /// The list has no `! Checksum:` line
+    Missing,
+    /// The checksum does not match and the list does not end with a line break,
+    /// it was most likely cut off while downloading. Only reported for lists cut
+    /// in the middle of a line: a list cut right after a line break gets `Mismatch`
+    Truncated,
+    Mismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+/**
+ * How many lines of a list this engine understands, see `coverage_report`.
+ * Every non-empty line counts in exactly one of the groups.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoverageReport {
+    /// Comments and the `[Adblock Plus]` header
+    pub comments: usize,
+    pub network: usize,
+    pub cosmetic: usize,
+    /// Rules in syntaxes not supported by design, e.g. AdGuard `#$#`, `#%#` and `$$` rules
+    pub ignored: usize,
+    /// Network filters that were rejected, by the option they were rejected for, e.g. `popup` or `~important`
+    pub unsupported_options: HashMap<String, usize>,
+    /// Network filters rejected for another reason, e.g. an invalid regex
+    pub unsupported_other: usize,
+}
+
+impl CoverageReport {
+    pub fn unsupported(&self) -> usize {
+        self.unsupported_options.values().sum::<usize>() + self.unsupported_other
+    }
+
+    /// Share of the rules, leaving out comments, that are loaded by the engine.
+    pub fn supported_ratio(&self) -> f64 {
+        let supported = self.network + self.cosmetic;
+        let rules = supported + self.ignored + self.unsupported();
+        if rules == 0 {
+            1.0
+        } else {
+            supported as f64 / rules as f64
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FilterList {
     pub uuid: String,
@@ -30,45 +82,338 @@ pub struct FilterList {
     pub base64_public_key: String,
 }
 
+/**
+ * Information from the `! Key: value` comments at the top of a list. Any
+ * field the list does not have is `None`.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilterListMetadata {
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub last_modified: Option<String>,
+    /// How long the list can be used before it should be downloaded again
+    pub expires: Option<Duration>,
+    pub homepage: Option<String>,
+    pub license: Option<String>,
+}
+
+impl FilterListMetadata {
+    /**
+     * Reads the header of a list, made of the comments before its first rule.
+     * Values that cannot be understood, such as an `Expires` without a number
+     * of days or hours, are skipped.
+     */
+    pub fn parse(list: &[String]) -> FilterListMetadata {
+        let mut metadata = FilterListMetadata::default();
+        for line in list.iter().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with("[Adblock") {
+                continue;
+            }
+            if !line.starts_with('!') {
+                break;
+            }
+            let comment = &line[1..];
+            let (key, value) = match comment.find(':') {
+                Some(colon) => (comment[..colon].trim(), comment[colon + 1..].trim()),
+                None => continue,
+            };
+            if value.is_empty() {
+                continue;
+            }
+            let field = match key.to_ascii_lowercase().as_str() {
+                "title" => &mut metadata.title,
+                "version" => &mut metadata.version,
+                "last modified" => &mut metadata.last_modified,
+                "homepage" => &mut metadata.homepage,
+                "license" | "licence" => &mut metadata.license,
+                "expires" => {
+                    if metadata.expires.is_none() {
+                        metadata.expires = parse_expires(value);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if field.is_none() {
+                *field = Some(String::from(value));
+            }
+        }
+        metadata
+    }
+
+    /**
+     * Completes the metadata with a catalog entry from `filter_lists`: its
+     * title is the one shown to users, and its support URL stands in for a
+     * missing homepage.
+     */
+    pub fn merge(&mut self, list: &FilterList) -> &mut FilterListMetadata {
+        if !list.title.is_empty() {
+            self.title = Some(list.title.clone());
+        }
+        if self.homepage.is_none() && !list.support_url.is_empty() {
+            self.homepage = Some(list.support_url.clone());
+        }
+        self
+    }
+}
+
+/// Parses values like `4 days (update frequency)` or `12 hours`.
+fn parse_expires(value: &str) -> Option<Duration> {
+    let mut words = value.split_whitespace();
+    let amount: u64 = words.next()?.parse().ok()?;
+    let unit = words.next().unwrap_or("days");
+    let seconds = if unit.starts_with("hour") || unit == "h" {
+        60 * 60
+    } else if unit.starts_with("day") || unit == "d" {
+        24 * 60 * 60
+    } else {
+        return None;
+    };
+    Some(Duration::from_secs(amount * seconds))
+}
+
+/// A line of a list that looked like a network filter but could not be parsed.
+#[derive(Debug, PartialEq)]
+pub struct ParseDiagnostic {
+    /// Starting at 1
+    pub line_number: usize,
+    pub line: String,
+    pub error: NetworkFilterError,
+}
+
 pub fn parse_filters(
     list: &[String],
     load_network_filters: bool,
     load_cosmetic_filters: bool,
     debug: bool,
 ) -> (Vec<NetworkFilter>, Vec<String>) {
+    let (network_filters, cosmetic_filters, _) = parse_filters_with_diagnostics(list, load_network_filters, load_cosmetic_filters, debug);
+    (network_filters, cosmetic_filters)
+}
+
+/**
+ * Same as `parse_filters`, marking the network filters as coming from the list
+ * `list_id`, e.g. the `uuid` of a `FilterList`. It is kept through
+ * optimizations and serialization, and returned in `BlockerResult` along with
+ * the filter that matched.
+ */
+pub fn parse_filters_for_list(
+    list: &[String],
+    list_id: &str,
+    load_network_filters: bool,
+    load_cosmetic_filters: bool,
+    debug: bool,
+) -> (Vec<NetworkFilter>, Vec<String>) {
+    let (mut network_filters, cosmetic_filters) = parse_filters(list, load_network_filters, load_cosmetic_filters, debug);
+    let list_id: Arc<str> = list_id.into();
+    for filter in network_filters.iter_mut() {
+        filter.list_id = Some(Arc::clone(&list_id));
+    }
+    (network_filters, cosmetic_filters)
+}
+
+/**
+ * Same as `parse_filters`, also returning every network filter that was
+ * rejected, with the reason. Comments and filters of a kind that is not
+ * loaded or not supported, e.g. AdGuard `#$#` rules, are left out on purpose
+ * and not reported.
+ */
+pub fn parse_filters_with_diagnostics(
+    list: &[String],
+    load_network_filters: bool,
+    load_cosmetic_filters: bool,
+    debug: bool,
+) -> (Vec<NetworkFilter>, Vec<String>, Vec<ParseDiagnostic>) {
+    let mut network_filters = Vec::new();
+    let mut cosmetic_filters = Vec::new();
+    let mut diagnostics = Vec::new();
+
+    for (index, line) in list.iter().enumerate() {
+        let filter = line.trim();
+        if filter.is_empty() {
+            continue;
+        }
+        let filter_type = detect_filter_type(filter);
+        if filter_type == FilterType::Network && load_network_filters {
+            match NetworkFilter::parse(filter, debug) {
+                Ok(network_filter) => network_filters.push(network_filter),
+                Err(error) => diagnostics.push(ParseDiagnostic {
+                    line_number: index + 1,
+                    line: line.clone(),
+                    error,
+                }),
+            }
+        } else if filter_type == FilterType::Cosmetic && load_cosmetic_filters {
+            // TODO: unimplemented, just return rule as a string
+            cosmetic_filters.push(String::from(filter));
+        }
+    }
+
+    (network_filters, cosmetic_filters, diagnostics)
+}
+
+/**
+ * Parses network filters straight from `reader`, e.g. a `BufReader` over a
+ * file or a `GzDecoder` over a cached list, one line at a time: only the line
+ * being parsed is kept in memory, unlike with `parse_filters`. Only the text
+ * of the list is streamed, the filters are returned one by one for the caller
+ * to keep. Lines that are not network filters are skipped, as with
+ * `parse_filters`; those that cannot be parsed are skipped as well and kept in
+ * `diagnostics`. Lines that are not valid UTF-8 are read with replacement
+ * characters.
+ */
+pub struct NetworkFilterReader<R> {
+    reader: R,
+    line: Vec<u8>,
+    line_number: usize,
+    debug: bool,
+    list_id: Option<Arc<str>>,
+    diagnostics: Vec<ParseDiagnostic>,
+}
 
-    let list_iter = list.iter();
+impl<R: BufRead> NetworkFilterReader<R> {
+    pub fn new(reader: R, debug: bool) -> NetworkFilterReader<R> {
+        NetworkFilterReader {
+            reader,
+            line: Vec::with_capacity(256),
+            line_number: 0,
+            debug,
+            list_id: None,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Marks the filters as coming from list `list_id`, see `parse_filters_for_list`.
+    pub fn for_list(mut self, list_id: &str) -> NetworkFilterReader<R> {
+        self.list_id = Some(list_id.into());
+        self
+    }
 
-    let (network_filters, cosmetic_filters): (Vec<_>, Vec<_>) = list_iter
-        .map(|line| {
+    /// Network filters rejected among the lines read so far, see `parse_filters_with_diagnostics`.
+    pub fn diagnostics(&self) -> &[ParseDiagnostic] {
+        &self.diagnostics
+    }
+}
+
+impl<R: BufRead> Iterator for NetworkFilterReader<R> {
+    type Item = std::io::Result<NetworkFilter>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => (),
+                Err(e) => return Some(Err(e)),
+            }
+            self.line_number += 1;
+            let line = String::from_utf8_lossy(&self.line);
             let filter = line.trim();
-            if !filter.is_empty() {
-                let filter_type = detect_filter_type(filter);
-                if filter_type == FilterType::Network && load_network_filters {
-                    let network_filter = NetworkFilter::parse(filter, debug);
-                    // if debug && network_filter.is_err() {
-                    //     println!("Error parsing rule {}: {:?}", filter, network_filter.as_ref().err())
-                    // }
-                    network_filter
-                        .map(Either::Left)
-                        .or_else(|_| Err(FilterError::ParseError))
-                } else if filter_type == FilterType::Cosmetic && load_cosmetic_filters {
-                    // TODO: unimplemented, just return rule as a string
-                    Ok(Either::Right(String::from(filter)))
-                } else {
-                    Err(FilterError::NotSupported)
+            if filter.is_empty() || detect_filter_type(filter) != FilterType::Network {
+                continue;
+            }
+            match NetworkFilter::parse(filter, self.debug) {
+                Ok(mut network_filter) => {
+                    network_filter.list_id = self.list_id.as_ref().map(Arc::clone);
+                    return Some(Ok(network_filter));
                 }
-            } else {
-                Err(FilterError::Empty)
+                Err(error) => self.diagnostics.push(ParseDiagnostic {
+                    line_number: self.line_number,
+                    line: String::from(line.trim_end_matches(&['\n', '\r'][..])),
+                    error,
+                }),
             }
+        }
+    }
+}
+
+/**
+ * Sorts the lines of `list` into supported network and cosmetic filters, rules
+ * ignored by design and rejected network filters, the latter by the option
+ * they were rejected for. Like `tests/ublock-coverage.rs`, but for any list.
+ */
+pub fn coverage_report(list: &[String]) -> CoverageReport {
+    let mut report = CoverageReport::default();
+    for line in list.iter().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+        if is_comment(line) {
+            report.comments += 1;
+            continue;
+        }
+        match detect_filter_type(line) {
+            FilterType::Cosmetic => report.cosmetic += 1,
+            FilterType::NotSupported => report.ignored += 1,
+            FilterType::Network => match NetworkFilter::parse(line, false) {
+                Ok(_) => report.network += 1,
+                Err(error) => match unsupported_option(&error, line) {
+                    Some(option) => *report.unsupported_options.entry(String::from(option)).or_insert(0) += 1,
+                    None => report.unsupported_other += 1,
+                },
+            },
+        }
+    }
+    report
+}
+
+/// The option a network filter was rejected for, if any.
+fn unsupported_option<'a>(error: &NetworkFilterError, line: &'a str) -> Option<&'a str> {
+    match error {
+        NetworkFilterError::UnrecognisedOption => unrecognised_option(line),
+        NetworkFilterError::NegatedBadFilter => Some("~badfilter"),
+        NetworkFilterError::NegatedImportant => Some("~important"),
+        NetworkFilterError::NegatedOptionMatchCase => Some("~match-case"),
+        NetworkFilterError::NegatedExplicitCancel => Some("~explicitcancel"),
+        NetworkFilterError::NegatedRedirection => Some("~redirect"),
+        NetworkFilterError::NegatedTag => Some("~tag"),
+        NetworkFilterError::EmptyRedirection => Some("redirect"),
+        _ => None,
+    }
+}
+
+/**
+ * Checks the `! Checksum:` line of an Adblock Plus list against its content,
+ * as downloaded, before it gets parsed. The checksum is the base64-encoded MD5
+ * of the list without the checksum line, carriage returns and empty lines.
+ * Lists that do not carry a checksum, as most non-ABP lists, get `ChecksumError::Missing`.
+ */
+pub fn validate_checksum(list: &str) -> Result<(), ChecksumError> {
+    lazy_static! {
+        static ref CHECKSUM_RE: Regex = Regex::new(r"(?mi)^\s*!\s*checksum[\s\-:]+([\w+/=]+).*(\n|\z)").unwrap();
+        static ref LINE_BREAKS_RE: Regex = Regex::new(r"\n+").unwrap();
+    }
+
+    let expected = CHECKSUM_RE
+        .captures(list)
+        .and_then(|captures| captures.get(1))
+        .map(|checksum| checksum.as_str().trim_end_matches('='))
+        .ok_or(ChecksumError::Missing)?;
+
+    let normalized = list.replace('\r', "");
+    let normalized = LINE_BREAKS_RE.replace_all(&normalized, "\n");
+    let normalized = CHECKSUM_RE.replace_all(&normalized, "");
+    let actual = base64::encode(&md5::compute(normalized.as_bytes()).0);
+    let actual = actual.trim_end_matches('=');
+
+    if actual == expected {
+        Ok(())
+    } else if !list.ends_with('\n') {
+        Err(ChecksumError::Truncated)
+    } else {
+        Err(ChecksumError::Mismatch {
+            expected: String::from(expected),
+            actual: String::from(actual),
         })
-        .filter_map(Result::ok)
-        .partition_map(|filter| match filter {
-            Either::Left(f) => Either::Left(f),
-            Either::Right(f) => Either::Right(f),
-        });
+    }
+}
 
-    (network_filters, cosmetic_filters)
+/// Same as `parse_filters`, also returning the metadata in the header of the list.
+pub fn parse_filters_with_metadata(
+    list: &[String],
+    load_network_filters: bool,
+    load_cosmetic_filters: bool,
+    debug: bool,
+) -> (Vec<NetworkFilter>, Vec<String>, FilterListMetadata) {
+    let (network_filters, cosmetic_filters) = parse_filters(list, load_network_filters, load_cosmetic_filters, debug);
+    (network_filters, cosmetic_filters, FilterListMetadata::parse(list))
 }
 
 /**
@@ -79,11 +424,7 @@ pub fn parse_filters(
  */
 fn detect_filter_type(filter: &str) -> FilterType {
     // Ignore comments
-    if filter.len() == 1
-        || filter.starts_with('!')
-        || (filter.starts_with('#') && filter[1..].starts_with(char::is_whitespace))
-        || filter.starts_with("[Adblock")
-    {
+    if is_comment(filter) {
         return FilterType::NotSupported;
     }
 
@@ -124,3 +465,188 @@ fn detect_filter_type(filter: &str) -> FilterType {
     // Everything else is a network filter
     FilterType::Network
 }
+
+fn is_comment(filter: &str) -> bool {
+    filter.len() == 1
+        || filter.starts_with('!')
+        || (filter.starts_with('#') && filter[1..].starts_with(char::is_whitespace))
+        || filter.starts_with("[Adblock")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_metadata() {
+        let list = crate::utils::read_file_lines("data/test/easylist.txt");
+        let (network_filters, _, metadata) = parse_filters_with_metadata(&list, true, false, false);
+        assert!(!network_filters.is_empty());
+        assert_eq!(metadata, FilterListMetadata {
+            title: Some(String::from("EasyList")),
+            version: Some(String::from("201706161209")),
+            last_modified: Some(String::from("16 Jun 2017 12:09 UTC")),
+            expires: Some(Duration::from_secs(4 * 24 * 60 * 60)),
+            homepage: Some(String::from("https://easylist.to/")),
+            license: Some(String::from("https://easylist.to/pages/licence.html")),
+        });
+
+        let list = crate::utils::read_file_lines("data/test/abpjf.txt");
+        let metadata = FilterListMetadata::parse(&list);
+        assert_eq!(metadata.expires, Some(Duration::from_secs(6 * 60 * 60)));
+        assert_eq!(metadata.version.as_deref(), Some("201906261928"));
+        assert_eq!(metadata.license, None);
+    }
+
+    #[test]
+    fn metadata_stops_at_first_rule() {
+        let list: Vec<String> = [
+            "! Title: Mine",
+            "! Expires: soon",
+            "",
+            "||foo.com^",
+            "! Version: 2",
+        ].iter().map(|line| String::from(*line)).collect();
+        assert_eq!(FilterListMetadata::parse(&list), FilterListMetadata {
+            title: Some(String::from("Mine")),
+            ..FilterListMetadata::default()
+        });
+    }
+
+    #[test]
+    fn validates_checksum() {
+        let list = std::fs::read_to_string("data/test/easylist.txt").unwrap();
+        assert_eq!(validate_checksum(&list), Ok(()));
+        assert_eq!(validate_checksum(&list.replace('\n', "\r\n\n")), Ok(()));
+
+        let list = std::fs::read_to_string("data/test/easyprivacy.txt").unwrap();
+        assert_eq!(validate_checksum(&list), Ok(()));
+        let changed = list.replacen("||", "||x", 1);
+        assert_eq!(validate_checksum(&changed), Err(ChecksumError::Mismatch {
+            expected: String::from("FxnItHIzdAZ//z8KD0qPTQ"),
+            actual: String::from("7ipMM3Qe0PcKgr1RnEvcGg"),
+        }));
+        let list = std::fs::read_to_string("data/easylist.to/easylistgermany/easylistgermany.txt").unwrap();
+        assert_eq!(validate_checksum(&list), Ok(()));
+        let truncated = list.lines().take(100).collect::<Vec<_>>().join("\n");
+        assert_eq!(validate_checksum(&truncated), Err(ChecksumError::Truncated));
+
+        assert_eq!(validate_checksum("! Title: None\n||foo.com^\n"), Err(ChecksumError::Missing));
+        assert_eq!(validate_checksum("[Adblock Plus 2.0]\n||foo.com^\n! Checksum: mKQCLyNtCMz8mRQyjnu74Q"), Ok(()));
+    }
+
+    #[test]
+    fn reports_rejected_lines() {
+        let list: Vec<String> = [
+            "! comment",
+            "||foo.com^",
+            "",
+            "/ads/$popup",
+            "  ||bar.com^$redirect=  ",
+            "example.com##.ad",
+            "example.com#$#.ad { display: none }",
+            "/banner$~important",
+        ].iter().map(|line| String::from(*line)).collect();
+
+        let (network_filters, cosmetic_filters, diagnostics) = parse_filters_with_diagnostics(&list, true, true, false);
+        assert_eq!(network_filters.len(), 1);
+        assert_eq!(cosmetic_filters, vec![String::from("example.com##.ad")]);
+        let summary: Vec<_> = diagnostics.iter().map(|d| (d.line_number, d.line.as_str(), &d.error)).collect();
+        assert_eq!(summary, vec![
+            (4, "/ads/$popup", &NetworkFilterError::UnrecognisedOption),
+            (5, "  ||bar.com^$redirect=  ", &NetworkFilterError::EmptyRedirection),
+            (8, "/banner$~important", &NetworkFilterError::NegatedImportant),
+        ]);
+
+        let (network_filters, _) = parse_filters(&list, true, true, false);
+        assert_eq!(network_filters.len(), 1);
+    }
+
+    #[test]
+    fn reports_coverage() {
+        let list: Vec<String> = [
+            "[Adblock Plus 2.0]",
+            "! Title: Mine",
+            "",
+            "||foo.com^",
+            "/ads/$script",
+            "/ads/$popup",
+            "/pop/$third-party,popup",
+            "/gen/$generichide",
+            "/banner$~important",
+            "example.com##.ad",
+            "example.com#@#.ad",
+            "example.com#$#.ad { display: none }",
+            "example.com#%#window.ad = 1",
+            "example.com$$script[data-ad]",
+        ].iter().map(|line| String::from(*line)).collect();
+
+        let report = coverage_report(&list);
+        assert_eq!(report.comments, 2);
+        assert_eq!(report.network, 2);
+        assert_eq!(report.cosmetic, 2);
+        assert_eq!(report.ignored, 3);
+        assert_eq!(report.unsupported_options.get("popup"), Some(&2));
+        assert_eq!(report.unsupported_options.get("generichide"), Some(&1));
+        assert_eq!(report.unsupported_options.get("~important"), Some(&1));
+        assert_eq!(report.unsupported_other, 0);
+        assert_eq!(report.unsupported(), 4);
+        assert_eq!(report.supported_ratio(), 4.0 / 11.0);
+    }
+
+    #[test]
+    fn merges_catalog_entry() {
+        let list = crate::utils::read_file_lines("data/test/easylist.txt");
+        let catalog = crate::filter_lists::default::default_lists();
+
+        let mut metadata = FilterListMetadata::parse(&list);
+        metadata.merge(&catalog[0]);
+        assert_eq!(metadata.title.as_deref(), Some("EasyList"));
+        assert_eq!(metadata.homepage.as_deref(), Some("https://easylist.to/"));
+        assert_eq!(metadata.version.as_deref(), Some("201706161209"));
+
+        let mut metadata = FilterListMetadata::default();
+        metadata.merge(&crate::filter_lists::regions::regions()[1]);
+        assert_eq!(metadata.title.as_deref(), Some("BGR: Bulgarian Adblock list"));
+        assert_eq!(metadata.homepage.as_deref(), Some("https://stanev.org/abp/"));
+        assert_eq!(metadata.expires, None);
+    }
+
+    #[test]
+    fn reads_filters_from_gzip() {
+        use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+        use std::io::{BufReader, Write};
+
+        let list = crate::utils::read_file_lines("data/test/easylist.txt");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(list.join("\n").as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let reader = BufReader::new(GzDecoder::new(&compressed[..]));
+        let streamed: Vec<NetworkFilter> = NetworkFilterReader::new(reader, true)
+            .for_list("easylist")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let (parsed, _) = parse_filters(&list, true, false, true);
+        assert_eq!(streamed.len(), parsed.len());
+        for (streamed, parsed) in streamed.iter().zip(parsed.iter()) {
+            assert_eq!(streamed.to_string(), parsed.to_string());
+            assert_eq!(streamed.list_id.as_deref(), Some("easylist"));
+        }
+    }
+
+    #[test]
+    fn reads_filters_from_any_bytes() {
+        let bytes: &[u8] = b"! comment\r\n||a.com^\r\n\xff\xfe##.ad\n$invalid-option\n\n||b.com^";
+        let mut reader = NetworkFilterReader::new(bytes, true);
+        let filters: Vec<String> = reader.by_ref()
+            .map(|filter| filter.unwrap().to_string())
+            .collect();
+        assert_eq!(filters, vec!["||a.com^", "||b.com^"]);
+        assert_eq!(reader.diagnostics(), &[ParseDiagnostic {
+            line_number: 4,
+            line: String::from("$invalid-option"),
+            error: NetworkFilterError::UnrecognisedOption,
+        }]);
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::Mutex;
+
+use crate::utils::Hash;
+
+/// Structured events emitted by the `Blocker` while matching requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockerEvent {
+    /// One of the filter lists (importants, tagged, redirects, filters or exceptions)
+    /// was checked against a request.
+    ListChecked {
+        list: &'static str,
+        buckets_visited: usize,
+        filters_evaluated: usize,
+        matched: bool,
+    },
+    /// A filter with a `$redirect` option matched, but no resource with that name is loaded.
+    RedirectResourceMissing {
+        redirect: String,
+    },
+    /// A regex filter was evaluated, but its pattern could not be compiled,
+    /// so it can never match. Emitted every time such a filter is evaluated.
+    RegexCompileFailed {
+        filter: String,
+        error: String,
+    },
+    /// A bucket of filters loaded with `Engine::deserialize_indexed` could not be
+    /// decoded, so none of its filters can match. Emitted the first time the
+    /// bucket stored under `token` is looked at.
+    BucketDecodeFailed {
+        token: Hash,
+        error: String,
+    },
+}
+
+/// Receives events from the `Blocker`, e.g. to forward them to a metrics backend.
+pub trait BlockerObserver: Send + Sync {
+    fn on_event(&self, event: &BlockerEvent);
+}
+
+/// Observer that keeps every event in memory, mostly useful for testing.
+#[derive(Debug, Default)]
+pub struct MemoryObserver {
+    events: Mutex<Vec<BlockerEvent>>,
+}
+
+impl MemoryObserver {
+    pub fn events(&self) -> Vec<BlockerEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+}
+
+impl BlockerObserver for MemoryObserver {
+    fn on_event(&self, event: &BlockerEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+/// Observer printing the same tab-separated lines the `metrics` feature used to produce.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+pub struct PrintObserver {}
+
+#[cfg(feature = "metrics")]
+impl BlockerObserver for PrintObserver {
+    fn on_event(&self, event: &BlockerEvent) {
+        match event {
+            BlockerEvent::ListChecked { list, buckets_visited, filters_evaluated, matched } => {
+                print!("{}\t{}\t{}\t{}\t", list, matched, buckets_visited, filters_evaluated)
+            }
+            BlockerEvent::RedirectResourceMissing { redirect } => {
+                eprintln!("Matched rule with redirect option but did not find corresponding resource {}", redirect)
+            }
+            BlockerEvent::RegexCompileFailed { filter, error } => {
+                eprintln!("Regex for filter {} failed to compile: {}", filter, error)
+            }
+            BlockerEvent::BucketDecodeFailed { token, error } => {
+                eprintln!("Filters stored under token {} failed to deserialize: {}", token, error)
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::filters::network::{NetworkFilter, NetworkFilterMask, FilterPart};
+use crate::utils::Hash;
 use itertools::*;
-use std::collections::{HashMap, HashSet};
-use std::iter::FromIterator;
+use std::collections::BTreeMap;
 
 trait Optimization {
     fn fusion(&self, filters: &[NetworkFilter]) -> NetworkFilter;
@@ -15,12 +15,12 @@ trait Optimization {
 pub fn optimize(filters: Vec<NetworkFilter>) -> Vec<NetworkFilter> {
     let mut optimized: Vec<NetworkFilter> = Vec::new();
 
-    // let union_domain_group = UnionDomainGroup {};
-    // let (mut fused, unfused) = apply_optimisation(&union_domain_group, filters);
-    // optimized.append(&mut fused);
-    
+    let union_domain_group = UnionDomainGroup {};
+    let (mut fused, unfused) = apply_optimisation(&union_domain_group, filters);
+    optimized.append(&mut fused);
+
     let simple_pattern_group = SimplePatternGroup {};
-    let (mut fused, mut unfused) = apply_optimisation(&simple_pattern_group, filters);
+    let (mut fused, mut unfused) = apply_optimisation(&simple_pattern_group, unfused);
     optimized.append(&mut fused);
     
     // Append whatever is still left unfused
@@ -41,7 +41,8 @@ fn apply_optimisation<T: Optimization>(
             }
         });
 
-    let mut to_fuse: HashMap<String, Vec<NetworkFilter>> = HashMap::with_capacity(positive.len());
+    // Ordered by group key so that the fused output does not vary between runs
+    let mut to_fuse: BTreeMap<String, Vec<NetworkFilter>> = BTreeMap::new();
     positive
         .into_iter()
         .for_each(|f| insert_dup(&mut to_fuse, optimization.group_by_criteria(&f), f));
@@ -61,9 +62,9 @@ fn apply_optimisation<T: Optimization>(
     (fused, negative)
 }
 
-fn insert_dup<K, V>(map: &mut HashMap<K, Vec<V>>, k: K, v: V)
+fn insert_dup<K, V>(map: &mut BTreeMap<K, Vec<V>>, k: K, v: V)
 where
-    K: std::cmp::Ord + std::hash::Hash,
+    K: std::cmp::Ord,
 {
     map.entry(k).or_insert_with(Vec::new).push(v)
 }
@@ -118,13 +119,15 @@ impl Optimization for SimplePatternGroup {
         filter
     }
 
+    // Filters with domain options are only fused with filters restricted to the exact same domains
     fn group_by_criteria(&self, filter: &NetworkFilter) -> String {
-        format!("{:b}:{:?}", filter.mask, filter.is_complete_regex())
+        format!(
+            "{:b}:{:?}:{:?}:{:?}:{:?}",
+            filter.mask, filter.is_complete_regex(), filter.opt_domains, filter.opt_not_domains, filter.list_id
+        )
     }
     fn select(&self, filter: &NetworkFilter) -> bool {
         !filter.is_fuzzy()
-            && filter.opt_domains.is_none()
-            && filter.opt_not_domains.is_none()
             && !filter.is_hostname_anchor()
             && !filter.is_redirect()
             && !filter.is_csp()
@@ -132,6 +135,12 @@ impl Optimization for SimplePatternGroup {
     }
 }
 
+/**
+ * Fuses filters that only differ by the domains listed in their `$domain=`
+ * option. Excluded domains (`~domain`) have to be identical: a request from
+ * the union of included domains must still be rejected if it comes from one
+ * of them, whereas filters excluding different domains cannot be combined.
+ */
 struct UnionDomainGroup {}
 
 impl Optimization for UnionDomainGroup {
@@ -139,37 +148,23 @@ impl Optimization for UnionDomainGroup {
     fn fusion(&self, filters: &[NetworkFilter]) -> NetworkFilter {
         let base_filter = &filters[0]; // FIXME: can technically panic, if filters list is empty
         let mut filter = base_filter.clone();
-        let mut domains = HashSet::new();
-        let mut not_domains = HashSet::new();
-
-        filters.iter().for_each(|f| {
-            if let Some(opt_domains) = f.opt_domains.as_ref() {
-                for d in opt_domains {
-                    domains.insert(d);
-                }
-            }
-            if let Some(opt_not_domains) = f.opt_not_domains.as_ref() {
-                for d in opt_not_domains {
-                    not_domains.insert(d);
-                }
-            }
-        });
-
-        if !domains.is_empty() {
-            let mut domains = Vec::from_iter(domains.into_iter().cloned());
-            domains.sort();
-            let opt_domains_union = Some(domains.iter().fold(0, |acc, x| acc | x));
-            filter.opt_domains = Some(domains);
-            filter.opt_domains_union = opt_domains_union;
-        }
-        if !not_domains.is_empty() {
-            let mut domains = Vec::from_iter(not_domains.into_iter().cloned());
-            domains.sort();
-            let opt_not_domains_union = Some(domains.iter().fold(0, |acc, x| acc | x));
-            filter.opt_not_domains = Some(domains);
-            filter.opt_not_domains_union = opt_not_domains_union;
-        }
 
+        let mut domains: Vec<Hash> = filters.iter()
+            .flat_map(|f| f.opt_domains.as_deref().unwrap_or(&[]))
+            .cloned()
+            .collect();
+        domains.sort_unstable();
+        domains.dedup();
+        filter.opt_domains_union = Some(domains.iter().fold(0, |acc, x| acc | x));
+        filter.opt_domains = Some(domains.into());
+
+        let mut domains_text: Vec<&str> = filters.iter()
+            .flat_map(|f| f.opt_domains_text.as_deref().unwrap_or("").split('|'))
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        domains_text.sort_unstable();
+        domains_text.dedup();
+        filter.opt_domains_text = Some(domains_text.join("|").into());
 
         if base_filter.raw_line.is_some() {
             filter.raw_line = Some(
@@ -184,14 +179,17 @@ impl Optimization for UnionDomainGroup {
     }
 
     fn group_by_criteria(&self, filter: &NetworkFilter) -> String {
-        format!("{:?}:{}:{:b}:{:?}", filter.hostname.as_ref(), filter.filter.string_view().unwrap_or_default(), filter.mask, filter.redirect.as_ref())
+        format!(
+            "{:?}:{:?}:{:b}:{:?}:{:?}:{:?}:{:?}:{:?}",
+            filter.hostname, filter.filter, filter.mask, filter.redirect, filter.csp, filter.tag, filter.opt_not_domains, filter.list_id
+        )
     }
 
     fn select(&self, filter: &NetworkFilter) -> bool {
         !filter.is_fuzzy()
             && !filter.is_csp()
             && !filter.has_bug()
-            && (filter.opt_domains.is_some() || filter.opt_not_domains.is_some())
+            && filter.opt_domains.is_some()
     }
 }
 
@@ -318,6 +316,23 @@ mod optimization_tests_pattern_group {
         assert!(filter.matches(&Request::from_urls("https://example.com/analytics/v1/foobar", "https://foo.leadpages.net", "").unwrap()))
     }
 
+    #[test]
+    fn groups_patterns_of_the_same_list() {
+        let rules = |rules: &[&str]| rules.iter().map(|rule| String::from(*rule)).collect::<Vec<_>>();
+        let (mut filters, _) = lists::parse_filters_for_list(&rules(&["/analytics-v1.", "/v1/pixel?"]), "easylist", true, false, true);
+        let (regional, _) = lists::parse_filters_for_list(&rules(&["/api/v1/stat?", "/v1/ads/*"]), "regional", true, false, true);
+        filters.extend(regional);
+
+        let (mut fused, skipped) = apply_optimisation(&SimplePatternGroup {}, filters);
+        assert!(skipped.is_empty());
+        fused.sort_by_key(|filter| filter.list_id.clone());
+        let fused: Vec<_> = fused.iter().map(|filter| (filter.to_string(), filter.list_id.as_deref())).collect();
+        assert_eq!(fused, vec![
+            (String::from("/analytics-v1. <+> /v1/pixel?"), Some("easylist")),
+            (String::from("/api/v1/stat? <+> /v1/ads/*"), Some("regional")),
+        ]);
+    }
+
 }
 
 
@@ -413,4 +428,35 @@ mod optimization_tests_union_domain {
         assert!(filter.matches(&Request::from_urls("https://example.com/analytics-v1/foobar", "https://foo.leadpages.net", "").unwrap()) == false);
     }
 
+    #[test]
+    fn keeps_different_excluded_domains_apart() {
+        let rules = vec![
+            String::from("/analytics-v1$domain=google.com|~mail.google.com"),
+            String::from("/analytics-v1$domain=example.com|~mail.google.com"),
+            String::from("/analytics-v1$domain=example.org|~www.example.org"),
+            String::from("/analytics-v1$domain=~example.net"),
+        ];
+
+        let (filters, _) = lists::parse_filters(&rules, true, false, true);
+        let optimization = UnionDomainGroup {};
+        let (fused, skipped) = apply_optimisation(&optimization, filters);
+
+        assert_eq!(fused.len(), 1);
+        let filter = &fused[0];
+        assert_eq!(
+            filter.to_string(),
+            "/analytics-v1$domain=google.com|~mail.google.com <+> /analytics-v1$domain=example.com|~mail.google.com"
+        );
+        let mut expected_domains = [utils::fast_hash("example.com"), utils::fast_hash("google.com")];
+        expected_domains.sort();
+        assert_eq!(filter.opt_domains.as_deref(), Some(&expected_domains[..]));
+        assert_eq!(filter.opt_domains_union, Some(expected_domains[0] | expected_domains[1]));
+        assert_eq!(filter.to_rule(), "/analytics-v1$domain=example.com|google.com|~mail.google.com");
+
+        assert!(filter.matches(&Request::from_urls("https://example.com/analytics-v1/foobar", "https://google.com", "").unwrap()));
+        assert!(filter.matches(&Request::from_urls("https://example.com/analytics-v1/foobar", "https://example.com", "").unwrap()));
+        assert!(!filter.matches(&Request::from_urls("https://example.com/analytics-v1/foobar", "https://mail.google.com", "").unwrap()));
+
+        assert_eq!(skipped.len(), 2);
+    }
 }
@@ -0,0 +1,333 @@
+//! Evaluation of the `!#if`, `!#else`, `!#endif` and `!#include` directives of
+//! uBlock Origin and AdGuard lists, ahead of `lists::parse_filters`.
+
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+/// Limit on `!#include`s within included lists, which also stops include cycles.
+pub const MAX_INCLUDE_DEPTH: usize = 8;
+
+/**
+ * Names that are true in `!#if` conditions, e.g. `env_firefox`, `env_mobile` or
+ * `cap_html_filtering`. Any other name is false, like uBlock Origin does for
+ * names it does not know about.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PreprocessorEnv {
+    names: HashSet<String>,
+}
+
+impl PreprocessorEnv {
+    pub fn new(names: &[&str]) -> PreprocessorEnv {
+        PreprocessorEnv {
+            names: names.iter().map(|name| String::from(*name)).collect(),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: bool) -> &mut PreprocessorEnv {
+        if value {
+            self.names.insert(String::from(name));
+        } else {
+            self.names.remove(name);
+        }
+        self
+    }
+
+    pub fn is_set(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+}
+
+/**
+ * Returns the lines of `list` that apply in `env`, without the directives.
+ * Lines of a list named by `!#include` come in place of the directive; they
+ * are fetched with `loader`, which returns `None` for lists it cannot or will
+ * not load, see `file_loader`. Such includes are skipped.
+ *
+ * Conditions are made of names, `!`, `&&`, `||` and parentheses, e.g.
+ * `!#if env_chromium && !env_mobile`. A condition that cannot be parsed is
+ * false. An `!#if` left open extends to the end of the list.
+ */
+pub fn preprocess<L: Fn(&str) -> Option<String>>(list: &[String], env: &PreprocessorEnv, loader: &L) -> Vec<String> {
+    let mut output = Vec::with_capacity(list.len());
+    preprocess_into(list.iter().map(String::as_str), env, loader, 0, &mut output);
+    output
+}
+
+/**
+ * Loader of lists in `directory` for `preprocess`. Paths leaving the
+ * directory are refused, as uBlock Origin only includes lists from the same
+ * location as the including list.
+ */
+pub fn file_loader<P: AsRef<Path>>(directory: P) -> impl Fn(&str) -> Option<String> {
+    let directory: PathBuf = directory.as_ref().to_owned();
+    move |path: &str| {
+        let path = Path::new(path);
+        if !path.components().all(|component| matches!(component, Component::Normal(_))) {
+            return None;
+        }
+        std::fs::read_to_string(directory.join(path)).ok()
+    }
+}
+
+/// State of one `!#if` block.
+struct Conditional {
+    // whether lines before the `!#if` are kept
+    outer: bool,
+    condition: bool,
+    in_else: bool,
+}
+
+impl Conditional {
+    fn active(&self) -> bool {
+        self.outer && (self.condition != self.in_else)
+    }
+}
+
+fn preprocess_into<'a, I, L>(lines: I, env: &PreprocessorEnv, loader: &L, depth: usize, output: &mut Vec<String>)
+where
+    I: Iterator<Item = &'a str>,
+    L: Fn(&str) -> Option<String>,
+{
+    let mut conditionals: Vec<Conditional> = Vec::new();
+    for line in lines {
+        let active = conditionals.last().map(|c| c.active()).unwrap_or(true);
+        let trimmed = line.trim();
+        match directive(trimmed) {
+            Some(("if", condition)) => conditionals.push(Conditional {
+                outer: active,
+                condition: evaluate(condition, env),
+                in_else: false,
+            }),
+            Some(("else", _)) => {
+                if let Some(conditional) = conditionals.last_mut() {
+                    conditional.in_else = true;
+                }
+            }
+            Some(("endif", _)) => {
+                conditionals.pop();
+            }
+            Some(("include", path)) => {
+                if active && depth < MAX_INCLUDE_DEPTH {
+                    if let Some(included) = loader(path) {
+                        preprocess_into(included.lines(), env, loader, depth + 1, output);
+                    }
+                }
+            }
+            _ => {
+                if active {
+                    output.push(String::from(line));
+                }
+            }
+        }
+    }
+}
+
+/// Name and argument of a `!#name argument` directive line.
+fn directive(line: &str) -> Option<(&str, &str)> {
+    if !line.starts_with("!#") {
+        return None;
+    }
+    let line = &line[2..];
+    let (name, argument) = match line.find(char::is_whitespace) {
+        Some(end) => (&line[..end], line[end..].trim()),
+        None => (line, ""),
+    };
+    match name {
+        "if" | "else" | "endif" | "include" => Some((name, argument)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Name(&'a str),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(condition: &str) -> Option<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut rest = condition.trim_start();
+    while !rest.is_empty() {
+        let (token, len) = if rest.starts_with("&&") {
+            (Token::And, 2)
+        } else if rest.starts_with("||") {
+            (Token::Or, 2)
+        } else if rest.starts_with('!') {
+            (Token::Not, 1)
+        } else if rest.starts_with('(') {
+            (Token::Open, 1)
+        } else if rest.starts_with(')') {
+            (Token::Close, 1)
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if len == 0 {
+                return None;
+            }
+            (Token::Name(&rest[..len]), len)
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Some(tokens)
+}
+
+fn evaluate(condition: &str, env: &PreprocessorEnv) -> bool {
+    tokenize(condition)
+        .and_then(|tokens| {
+            let mut parser = Parser { tokens: &tokens, position: 0, env };
+            parser.or().filter(|_| parser.position == tokens.len())
+        })
+        .unwrap_or(false)
+}
+
+/// Recursive descent over a tokenized condition, `None` when it is malformed.
+struct Parser<'a, 'b> {
+    tokens: &'a [Token<'b>],
+    position: usize,
+    env: &'a PreprocessorEnv,
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn next_is(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.position) == Some(token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Option<bool> {
+        let mut value = self.and()?;
+        while self.next_is(&Token::Or) {
+            value = self.and()? || value;
+        }
+        Some(value)
+    }
+
+    fn and(&mut self) -> Option<bool> {
+        let mut value = self.unary()?;
+        while self.next_is(&Token::And) {
+            value = self.unary()? && value;
+        }
+        Some(value)
+    }
+
+    fn unary(&mut self) -> Option<bool> {
+        if self.next_is(&Token::Not) {
+            return self.unary().map(|value| !value);
+        }
+        if self.next_is(&Token::Open) {
+            let value = self.or()?;
+            return if self.next_is(&Token::Close) { Some(value) } else { None };
+        }
+        match self.tokens.get(self.position) {
+            Some(Token::Name(name)) => {
+                self.position += 1;
+                Some(self.env.is_set(name))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(list: &str) -> Vec<String> {
+        list.lines().map(String::from).collect()
+    }
+
+    fn no_includes(_path: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        let env = PreprocessorEnv::new(&["env_firefox", "cap_html_filtering"]);
+        assert!(evaluate("env_firefox", &env));
+        assert!(!evaluate("env_chromium", &env));
+        assert!(evaluate("!env_chromium", &env));
+        assert!(evaluate("env_chromium || env_firefox", &env));
+        assert!(!evaluate("env_firefox && env_mobile", &env));
+        assert!(evaluate("env_firefox && !(env_mobile || env_safari)", &env));
+        assert!(evaluate("!!cap_html_filtering", &env));
+        assert!(evaluate("(adguard || env_firefox)&&cap_html_filtering", &env));
+        // malformed
+        assert!(!evaluate("", &env));
+        assert!(!evaluate("env_firefox &&", &env));
+        assert!(!evaluate("(env_firefox", &env));
+        assert!(!evaluate("env_firefox)", &env));
+        assert!(!evaluate("env_firefox env_firefox", &env));
+        assert!(!evaluate("env-firefox", &env));
+    }
+
+    #[test]
+    fn keeps_lines_of_true_branches() {
+        let list = lines("
+a
+!#if env_firefox
+b
+!#if env_mobile
+c
+!#else
+d
+!#endif
+!#else
+e
+!#if !env_mobile
+f
+!#endif
+!#endif
+  !#if false
+g
+  !#endif
+! #if is a comment
+!#endif
+h
+!#if env_mobile
+i");
+        let mut env = PreprocessorEnv::new(&["env_firefox"]);
+        assert_eq!(preprocess(&list, &env, &no_includes), lines("\na\nb\nd\n! #if is a comment\nh"));
+        env.set("env_firefox", false).set("env_mobile", true);
+        assert_eq!(preprocess(&list, &env, &no_includes), lines("\na\ne\n! #if is a comment\nh\ni"));
+    }
+
+    #[test]
+    fn resolves_includes() {
+        let loader = |path: &str| match path {
+            "sub.txt" => Some(String::from("sub1\n!#if env_mobile\nsub2\n!#endif\n!#include nested.txt")),
+            "nested.txt" => Some(String::from("nested")),
+            "cycle.txt" => Some(String::from("cycle\n!#include cycle.txt")),
+            _ => None,
+        };
+        let env = PreprocessorEnv::default();
+        let list = lines("a\n!#include sub.txt\n!#if env_mobile\n!#include nested.txt\n!#endif\n!#include missing.txt\nb");
+        assert_eq!(preprocess(&list, &env, &loader), lines("a\nsub1\nnested\nb"));
+
+        let cycle = preprocess(&lines("!#include cycle.txt"), &env, &loader);
+        assert_eq!(cycle.len(), MAX_INCLUDE_DEPTH);
+    }
+
+    #[test]
+    fn loads_files_from_directory() {
+        let loader = file_loader("data/test/preprocessor");
+        assert!(loader("sub.txt").is_some());
+        assert!(loader("../easylist.txt").is_none());
+        assert!(loader("/etc/hostname").is_none());
+
+        let list = crate::utils::read_file_lines("data/test/preprocessor/list.txt");
+        let rules = preprocess(&list, &PreprocessorEnv::new(&["env_firefox"]), &loader);
+        let (network_filters, _) = crate::lists::parse_filters(&rules, true, false, true);
+        let filters: Vec<_> = network_filters.iter().map(|filter| filter.to_string()).collect();
+        assert_eq!(filters, vec!["||everywhere.com^", "||firefox-only.com^", "||included.com^"]);
+    }
+}
@@ -4,7 +4,7 @@ use crate::utils;
 use idna;
 use std::sync::{Arc, RwLock};
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum RequestType {
     Beacon,
     Csp,
@@ -85,7 +85,9 @@ pub struct Request {
     // mutable fields, set later
     pub bug: Option<u32>,
     fuzzy_signature: Arc<RwLock<Option<Vec<utils::Hash>>>>, // evaluated lazily
-    hostname_end: usize
+    hostname_end: usize,
+    // URL before lowercasing, for `$match-case` filters, if it differs from `url`
+    original_url: Option<String>,
 }
 
 impl<'a> Request {
@@ -208,8 +210,32 @@ impl<'a> Request {
             is_supported,
             bug: None,
             fuzzy_signature: Arc::new(RwLock::new(None)),
-            hostname_end
+            hostname_end,
+            original_url: None,
+        }
+    }
+
+    /// Keeps `original_url` for `$match-case` filters if it only differs from `url` in case.
+    fn with_original_url(mut self, original_url: &str) -> Request {
+        if original_url != self.url && original_url.len() == self.url.len() && original_url.eq_ignore_ascii_case(&self.url) {
+            self.original_url = Some(original_url.to_owned());
         }
+        self
+    }
+
+    /// URL in its original case.
+    pub(crate) fn original_url(&self) -> &str {
+        self.original_url.as_deref().unwrap_or(&self.url)
+    }
+
+    /// Same request with the URL in its original case, if it was lowercased.
+    pub(crate) fn with_original_case(&self) -> Option<Request> {
+        self.original_url.as_ref().map(|original_url| Request {
+            url: original_url.clone(),
+            fuzzy_signature: Arc::new(RwLock::new(None)),
+            original_url: None,
+            ..self.clone()
+        })
     }
 
     pub fn from_urls(
@@ -218,6 +244,13 @@ impl<'a> Request {
         request_type: &str,
     ) -> Result<Request, RequestError> {
         if let Some(parsed_url) = Request::parse_url(&url) {
+            // Everything after the hostname is lowercased by the parser, but otherwise kept as is
+            let host_end = parsed_url.hostname_pos.1;
+            let trimmed = url.trim();
+            let original_url = trimmed
+                .get(trimmed.len().saturating_sub(parsed_url.url.len() - host_end)..)
+                .map(|path| format!("{}{}", &parsed_url.url[..host_end], path))
+                .unwrap_or_default();
             if let Some(parsed_source) = Request::parse_url(&source_url) {
                 let source_domain = parsed_source.domain();
 
@@ -236,7 +269,7 @@ impl<'a> Request {
                     source_domain,
                     third_party,
                     parsed_url.hostname_pos.1
-                ))
+                ).with_original_url(&original_url))
             } else {
                 Ok(Request::from_detailed_parameters(
                     request_type,
@@ -247,7 +280,7 @@ impl<'a> Request {
                     "",
                     None,
                     parsed_url.hostname_pos.1
-                ))
+                ).with_original_url(&original_url))
             }
         } else {
             return Err(RequestError::HostnameParseError);
@@ -290,7 +323,7 @@ impl<'a> Request {
             &source_domain,
             third_party,
             splitter + 2 + hostname.len()
-        )
+        ).with_original_url(url)
     }
 
     pub fn from_url(url: &str) -> Result<Request, RequestError> {
@@ -411,6 +444,21 @@ mod tests {
         tokens
     }
 
+    #[test]
+    fn keeps_original_case() {
+        let request = Request::from_urls("https://foo.com/AdBlock.js?X=1", "", "").unwrap();
+        assert_eq!(request.url, "https://foo.com/adblock.js?x=1");
+        assert_eq!(request.with_original_case().unwrap().url, "https://foo.com/AdBlock.js?X=1");
+        assert_eq!(request.with_original_case().unwrap().url_after_hostname(), "/AdBlock.js?X=1");
+
+        let request = Request::from_urls("https://foo.com/adblock.js", "", "").unwrap();
+        assert!(request.with_original_case().is_none());
+
+        let request = Request::from_urls_with_hostname("https://foo.com/AdBlock.js", "foo.com", "", "", None);
+        assert_eq!(request.url, "https://foo.com/adblock.js");
+        assert_eq!(request.with_original_case().unwrap().url, "https://foo.com/AdBlock.js");
+    }
+
     #[test]
     fn get_fuzzy_signature_works() {
         let simple_example = Request::new(
@@ -8,8 +8,9 @@ pub struct Resource {
     pub data: String
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Resources {
+    #[serde(serialize_with = "crate::serialization::serialize_sorted_map")]
     pub resources: HashMap<String, Resource>
 }
 
@@ -1,101 +1,533 @@
-use serde;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Serializer, Deserialize};
 
 use flate2::write::GzEncoder;
 use flate2::read::GzDecoder;
 use flate2::Compression;
 
-use bincode;
+use hashbrown::HashMap;
+use once_cell::unsync::OnceCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::sync::Arc;
 
-use crate::blocker::Blocker;
+use crate::blocker::{Blocker, BlockerError};
+use crate::filters::network::NetworkFilter;
+use crate::observer::{BlockerEvent, BlockerObserver};
+use crate::utils::Hash;
 
 // Pick version to use for serialization from cargo package version
-pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-
-// Helper structs to use the wrapped Blocker struct with its own serialization definitions
-pub struct Wrapper<'a> {
-    pub wrapped: &'a Blocker
-}
-
-pub struct Unwrappable {
-    pub wrapped: Box<Blocker>
-}
-
-impl<'a> serde::Serialize for Wrapper<'a> {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        // An intermediate struct that adds manifest version and includes
-        // the wrapped structure already encoded. Allows for checking of
-        // `manifest-version` before any other field gets decoded
-        #[derive(Serialize)]
-        struct EncodedBlocker<'b> {
-            #[serde(rename = "manifest-version")]
-            manifest_version: &'b str,
-            blocker: &'b Vec<u8>
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Leading bytes identifying a serialized engine in the versioned format.
+pub const MAGIC: [u8; 4] = *b"ABRS";
+
+/// Version of the on-disk layout. Bump whenever a change to the serialized
+/// structures makes previously written data unreadable.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Leading bytes identifying a serialized engine in the indexed layout, which is
+/// not compressed and can be queried in place, see `serialize_indexed`.
+pub const INDEXED_MAGIC: [u8; 4] = *b"ABRI";
+
+// Each bucket index entry is a little-endian (token: u64, offset: u32, length: u32)
+const BUCKET_ENTRY_SIZE: usize = 16;
+// Magic, format version and length of the metadata section
+const INDEXED_PREAMBLE_SIZE: usize = 12;
+
+// Legacy `.dat` files are bare gzip streams of the rmp-encoded `Blocker`
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// An intermediate struct that is decoded before the rest of the data. Allows
+// for checking of `format-version` before any other field gets decoded.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    #[serde(rename = "format-version")]
+    format_version: u32,
+    #[serde(rename = "manifest-version")]
+    manifest_version: String,
+}
+
+/**
+ * Serializes the `Blocker` as:
+ * - `MAGIC`
+ * - rmp-encoded `Header` with the format and crate versions
+ * - gzip'd rmp-encoded `Blocker`
+ */
+pub fn serialize(blocker: &Blocker) -> Result<Vec<u8>, BlockerError> {
+    let mut output = Vec::new();
+    output.extend_from_slice(&MAGIC);
+
+    let header = Header {
+        format_version: FORMAT_VERSION,
+        manifest_version: String::from(VERSION),
+    };
+    rmps::encode::write(&mut output, &header)
+        .map_err(|e| {
+            eprintln!("Error serializing header: {:?}", e);
+            BlockerError::SerializationError
+        })?;
+
+    let mut gz = GzEncoder::new(output, Compression::default());
+    rmps::encode::write(&mut gz, blocker)
+        .map_err(|e| {
+            eprintln!("Error serializing: {:?}", e);
+            BlockerError::SerializationError
+        })?;
+
+    gz.finish().map_err(|_| BlockerError::SerializationError)
+}
+
+/**
+ * Deserializes data written by `serialize`. Data written before the format
+ * was versioned (a bare gzip stream) is still accepted.
+ */
+pub fn deserialize(serialized: &[u8]) -> Result<Blocker, BlockerError> {
+    if serialized.starts_with(&MAGIC) {
+        let mut remaining = &serialized[MAGIC.len()..];
+        let header: Header = rmps::decode::from_read(&mut remaining)
+            .map_err(|e| {
+                eprintln!("Error deserializing header: {:?}", e);
+                BlockerError::DeserializationError
+            })?;
+
+        if header.format_version != FORMAT_VERSION {
+            return Err(BlockerError::DeserializationVersionMismatch {
+                format_version: header.format_version,
+                manifest_version: header.manifest_version,
+            });
         }
 
-        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        decode_blocker(remaining)
+    } else if serialized.starts_with(&GZIP_MAGIC) {
+        decode_blocker(serialized)
+    } else {
+        Err(BlockerError::DeserializationError)
+    }
+}
+
+/**
+ * Serializes the `Blocker` in the indexed layout:
+ * - `INDEXED_MAGIC`
+ * - `FORMAT_VERSION` as a little-endian u32
+ * - length of the metadata section as a little-endian u32
+ * - rmp-encoded metadata: versions, options, resources and where each list's bucket index is
+ * - the body: every bucket rmp-encoded on its own, followed by a bucket index per list
+ *
+ * Bucket indices are sorted by token and have fixed-size entries, so a lookup is a binary
+ * search over the raw bytes and only the buckets that are actually visited get decoded.
+ */
+pub fn serialize_indexed(blocker: &Blocker) -> Result<Vec<u8>, BlockerError> {
+    let mut body = Vec::new();
+    let metadata = blocker.write_indexed(&mut body)?;
+    let mut metadata_encoded = Vec::new();
+    rmps::encode::write(&mut metadata_encoded, &metadata)
+        .map_err(|e| {
+            eprintln!("Error serializing metadata: {:?}", e);
+            BlockerError::SerializationError
+        })?;
+    let metadata_len = u32::try_from(metadata_encoded.len()).map_err(|_| BlockerError::SerializationError)?;
+
+    let mut output = Vec::with_capacity(INDEXED_PREAMBLE_SIZE + metadata_encoded.len() + body.len());
+    output.extend_from_slice(&INDEXED_MAGIC);
+    output.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    output.extend_from_slice(&metadata_len.to_le_bytes());
+    output.extend_from_slice(&metadata_encoded);
+    output.extend_from_slice(&body);
+    Ok(output)
+}
 
-        bincode::serialize_into(&mut gz, &self)
-            .or_else(|e| {
-                Err(D::Error::invalid_value(::serde::de::Unexpected::Other("Failed to serialize to bincode"), &e.to_string().as_str()))
+/**
+ * Loads a `Blocker` from data written by `serialize_indexed` without decoding
+ * its filters. The data is kept for the lifetime of the `Blocker` and can be
+ * anything that exposes bytes, e.g. a `Vec<u8>` or a memory-mapped file.
+ */
+pub fn deserialize_indexed<D: AsRef<[u8]> + 'static>(data: D) -> Result<Blocker, BlockerError> {
+    let data: Rc<dyn AsRef<[u8]>> = Rc::new(data);
+    let (metadata, body_start) = {
+        let bytes = (*data).as_ref();
+        if bytes.len() < INDEXED_PREAMBLE_SIZE || !bytes.starts_with(&INDEXED_MAGIC) {
+            return Err(BlockerError::DeserializationError);
+        }
+        let format_version = read_u32(bytes, 4);
+        let metadata_len = read_u32(bytes, 8) as usize;
+        let body_start = match INDEXED_PREAMBLE_SIZE.checked_add(metadata_len) {
+            Some(body_start) if body_start <= bytes.len() => body_start,
+            _ => return Err(BlockerError::DeserializationError),
+        };
+        let metadata: IndexedMetadata<crate::blocker::IndexedParts> = rmps::decode::from_slice(&bytes[INDEXED_PREAMBLE_SIZE..body_start])
+            .map_err(|e| {
+                eprintln!("Error deserializing metadata: {:?}", e);
+                BlockerError::DeserializationError
             })?;
-        
-        let compressed = gz.finish().unwrap();
-            .or_else(|e| {
-                Err(D::Error::invalid_value(::serde::de::Unexpected::Other("Failed to finish Gzip encoding"), &e.to_string().as_str()))
+        if format_version != FORMAT_VERSION {
+            return Err(BlockerError::DeserializationVersionMismatch {
+                format_version,
+                manifest_version: metadata.manifest_version,
+            });
+        }
+        (metadata, body_start)
+    };
+
+    Blocker::from_indexed(metadata, &data, body_start)
+}
+
+/// Everything in the indexed layout that is decoded up front. `T` holds the
+/// parts owned by the `Blocker` itself and is only interpreted there.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IndexedMetadata<T> {
+    #[serde(rename = "manifest-version")]
+    pub manifest_version: String,
+    pub lists: Vec<BucketIndex>,
+    pub blocker: T,
+}
+
+/// Where a single list's bucket index starts in the body, and how many buckets it has.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct BucketIndex {
+    offset: u32,
+    count: u32,
+}
+
+/// Appends every bucket of `filter_map` to `body`, followed by their index.
+pub(crate) fn write_buckets(body: &mut Vec<u8>, filter_map: &HashMap<Hash, Vec<Arc<NetworkFilter>>>) -> Result<BucketIndex, BlockerError> {
+    let mut tokens: Vec<&Hash> = filter_map.keys().collect();
+    tokens.sort();
+
+    let mut entries = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let start = body.len();
+        rmps::encode::write(body, &filter_map[token])
+            .map_err(|e| {
+                eprintln!("Error serializing bucket: {:?}", e);
+                BlockerError::SerializationError
             })?;
+        entries.push((*token, to_offset(start)?, to_offset(body.len() - start)?));
+    }
 
-        let output = EncodedBlocker {
-            // Pick version to use for serialization from cargo package version
-            manifest_version: VERSION,
-            blocker: &compressed,
+    let offset = to_offset(body.len())?;
+    for (token, start, len) in entries.iter() {
+        body.extend_from_slice(&token.to_le_bytes());
+        body.extend_from_slice(&start.to_le_bytes());
+        body.extend_from_slice(&len.to_le_bytes());
+    }
+
+    Ok(BucketIndex {
+        offset,
+        count: to_offset(entries.len())?,
+    })
+}
+
+fn to_offset(value: usize) -> Result<u32, BlockerError> {
+    u32::try_from(value).map_err(|_| BlockerError::SerializationError)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/**
+ * Buckets of a single list in the indexed layout. Each bucket is decoded the
+ * first time it is requested and kept afterwards. Every bucket is checked to be
+ * well-formed when loading, without decoding its filters; a bucket that still
+ * fails to decode is left empty and reported to the observer.
+ */
+pub(crate) struct LazyBuckets {
+    data: Rc<dyn AsRef<[u8]>>,
+    body_start: usize,
+    index_start: usize,
+    decoded: Vec<OnceCell<Vec<Arc<NetworkFilter>>>>,
+    observer: Option<Arc<dyn BlockerObserver>>,
+}
+
+impl LazyBuckets {
+    pub fn new(data: &Rc<dyn AsRef<[u8]>>, body_start: usize, index: BucketIndex) -> Result<LazyBuckets, BlockerError> {
+        let body_len = (**data).as_ref().len() - body_start;
+        let index_start = index.offset as usize;
+        let count = index.count as usize;
+        let index_end = count.checked_mul(BUCKET_ENTRY_SIZE)
+            .and_then(|size| size.checked_add(index_start))
+            .ok_or(BlockerError::DeserializationError)?;
+        if index_end > body_len {
+            return Err(BlockerError::DeserializationError);
+        }
+
+        let buckets = LazyBuckets {
+            data: Rc::clone(data),
+            body_start,
+            index_start,
+            decoded: std::iter::repeat_with(OnceCell::new).take(count).collect(),
+            observer: None,
         };
+        // Filters are only decoded on demand, here each bucket is just skipped over
+        for i in 0..count {
+            let (_, start, len) = buckets.entry(i);
+            match start.checked_add(len) {
+                Some(end) if end <= body_len => check_bucket(&buckets.body()[start..end])?,
+                _ => return Err(BlockerError::DeserializationError),
+            }
+        }
+        Ok(buckets)
+    }
+
+    fn body(&self) -> &[u8] {
+        &(*self.data).as_ref()[self.body_start..]
+    }
+
+    fn entry(&self, i: usize) -> (Hash, usize, usize) {
+        let at = self.index_start + i * BUCKET_ENTRY_SIZE;
+        let body = self.body();
+        (read_u64(body, at), read_u32(body, at + 8) as usize, read_u32(body, at + 12) as usize)
+    }
+
+    fn position(&self, token: Hash) -> Option<usize> {
+        let mut low = 0;
+        let mut high = self.decoded.len();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let (mid_token, _, _) = self.entry(mid);
+            if mid_token < token {
+                low = mid + 1;
+            } else if mid_token > token {
+                high = mid;
+            } else {
+                return Some(mid);
+            }
+        }
+        None
+    }
+
+    fn decode(&self, i: usize) -> &Vec<Arc<NetworkFilter>> {
+        self.decoded[i].get_or_init(|| {
+            let (token, start, len) = self.entry(i);
+            rmps::decode::from_slice(&self.body()[start..start + len])
+                .unwrap_or_else(|e| {
+                    match self.observer.as_ref() {
+                        Some(observer) => observer.on_event(&BlockerEvent::BucketDecodeFailed {
+                            token,
+                            error: e.to_string(),
+                        }),
+                        None => eprintln!("Error deserializing bucket: {:?}", e),
+                    }
+                    Vec::new()
+                })
+        })
+    }
+
+    /// Where to report buckets that fail to decode.
+    pub fn with_observer(&mut self, observer: Arc<dyn BlockerObserver>) {
+        self.observer = Some(observer);
+    }
+
+    pub fn get(&self, token: Hash) -> Option<&Vec<Arc<NetworkFilter>>> {
+        self.position(token).map(|i| self.decode(i))
+    }
+
+    pub fn len(&self) -> usize {
+        self.decoded.len()
+    }
 
-        // Once again, serde does all the hard work for us
-        output.serialize(serializer)
+    pub fn decoded_len(&self) -> usize {
+        self.decoded.iter().filter(|d| d.get().is_some()).count()
     }
+
+    /// Decodes all buckets, returning them with their tokens.
+    pub fn iter(&self) -> impl Iterator<Item = (Hash, &Vec<Arc<NetworkFilter>>)> {
+        (0..self.len()).map(move |i| (self.entry(i).0, self.decode(i)))
+    }
+}
+
+/// Checks that `bucket` holds exactly one encoded array, without decoding its items.
+fn check_bucket(mut bucket: &[u8]) -> Result<(), BlockerError> {
+    let mut deserializer = rmps::Deserializer::new(&mut bucket);
+    Vec::<serde::de::IgnoredAny>::deserialize(&mut deserializer)
+        .map_err(|e| {
+            eprintln!("Error deserializing bucket: {:?}", e);
+            BlockerError::DeserializationError
+        })?;
+    if !bucket.is_empty() {
+        return Err(BlockerError::DeserializationError);
+    }
+    Ok(())
+}
+
+/// Serializes any map with its entries sorted by key, so that the output does not
+/// depend on the iteration order of the underlying hash map.
+pub(crate) fn serialize_sorted_map<'a, M, K, V, S>(map: &'a M, serializer: S) -> Result<S::Ok, S::Error>
+where
+    &'a M: IntoIterator<Item = (&'a K, &'a V)>,
+    K: Serialize + Ord + 'a,
+    V: Serialize + 'a,
+    S: Serializer,
+{
+    let sorted: BTreeMap<&K, &V> = map.into_iter().collect();
+    sorted.serialize(serializer)
 }
 
-impl<'de> serde::Deserialize<'de> for Unwrappable {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        use serde::de::Error;
+fn decode_blocker(compressed: &[u8]) -> Result<Blocker, BlockerError> {
+    let gz = GzDecoder::new(compressed);
+    let mut blocker: Blocker = rmps::decode::from_read(gz)
+        .map_err(|e| {
+            eprintln!("Error deserializing: {:?}", e);
+            BlockerError::DeserializationError
+        })?;
+    // Every filter gets its own copy of shared data when decoded
+    blocker.compact();
+    Ok(blocker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocker::BlockerOptions;
+
+    fn empty_blocker() -> Blocker {
+        let blocker_options = BlockerOptions {
+            debug: false,
+            enable_optimizations: true,
+            load_cosmetic_filters: false,
+            load_network_filters: true
+        };
+        Blocker::new(Vec::new(), &blocker_options)
+    }
+
+    #[test]
+    fn writes_header() {
+        let serialized = serialize(&empty_blocker()).unwrap();
+        assert!(serialized.starts_with(&MAGIC));
 
-        // An intermediate struct that exactly matches the input schema.
-        #[derive(Deserialize)]
-        struct EncodedBlocker {
-            #[serde(rename = "manifest-version")]
-            pub manifest_version: String,
-            pub blocker: Vec<u8>
+        let mut remaining = &serialized[MAGIC.len()..];
+        let header: Header = rmps::decode::from_read(&mut remaining).unwrap();
+        assert_eq!(header.format_version, FORMAT_VERSION);
+        assert_eq!(header.manifest_version, VERSION);
+        assert!(remaining.starts_with(&GZIP_MAGIC));
+    }
+
+    #[test]
+    fn format_version_is_pinned() {
+        // A serialized `NetworkFilter` is an array of its fields. Adding one changes
+        // the layout, so `FORMAT_VERSION` has to be bumped along with this test.
+        assert_eq!(FORMAT_VERSION, 3);
+        let filter = NetworkFilter::parse("||example.com^", false).unwrap();
+        let encoded = rmps::encode::to_vec(&filter).unwrap();
+        let fields = match encoded[0] {
+            marker @ 0x90..=0x9f => (marker & 0x0f) as usize,
+            0xdc => u16::from_be_bytes([encoded[1], encoded[2]]) as usize,
+            marker => panic!("Not an array: {:#x}", marker),
+        };
+        assert_eq!(fields, 16);
+    }
+
+    #[test]
+    fn rejects_other_format_version() {
+        let mut serialized = MAGIC.to_vec();
+        let header = Header {
+            format_version: FORMAT_VERSION + 1,
+            manifest_version: String::from("99.0.0"),
+        };
+        rmps::encode::write(&mut serialized, &header).unwrap();
+        let blocker = serialize(&empty_blocker()).unwrap();
+        let mut remaining = &blocker[MAGIC.len()..];
+        let _: Header = rmps::decode::from_read(&mut remaining).unwrap();
+        serialized.extend_from_slice(remaining);
+
+        match deserialize(&serialized) {
+            Err(e) => assert_eq!(e, BlockerError::DeserializationVersionMismatch {
+                format_version: FORMAT_VERSION + 1,
+                manifest_version: String::from("99.0.0"),
+            }),
+            Ok(_) => panic!("Expected version mismatch"),
         }
+    }
 
-        // Because we derived Deserialize automatically,
-        // serde does all the hard work for us.
-        let input = EncodedBlocker::deserialize(deserializer)?;
+    #[test]
+    fn rejects_unknown_data() {
+        assert!(deserialize(b"not an engine").is_err());
+        assert!(deserialize(&[]).is_err());
+    }
 
-        // Validating the manifest_version field is straightforward.
-        if input.manifest_version != VERSION {
-            return Err(D::Error::invalid_value(
-                ::serde::de::Unexpected::Str(&input.manifest_version), &VERSION
-            ));
+    #[test]
+    fn lazy_buckets_decode_on_demand() {
+        let mut filter_map = HashMap::new();
+        for (token, rule) in [(3, "||foo.com^"), (1, "/banner/"), (2, "ads$image")].iter() {
+            let filter = NetworkFilter::parse(rule, true).unwrap();
+            filter_map.insert(*token as Hash, vec![filter.into()]);
         }
+        let mut body = b"prefix".to_vec();
+        let index = write_buckets(&mut body, &filter_map).unwrap();
 
-        let gz = GzDecoder::new(&input.blocker[..]);
-        let blocker = bincode::deserialize_from(gz)
-            .or_else(|e| {
-                Err(D::Error::invalid_value(::serde::de::Unexpected::Other("Failed to parse bincode formatted data"), &e.to_string().as_str()))
-            })?;
+        let data: Rc<dyn AsRef<[u8]>> = Rc::new(body);
+        let buckets = LazyBuckets::new(&data, 0, index).unwrap();
+        assert_eq!(buckets.len(), 3);
+        assert_eq!(buckets.decoded_len(), 0);
 
-        // Finally, we move all the data into an instance
-        // of our wrapper struct.
-        Ok(Unwrappable {
-            wrapped: Box::new(blocker)
-        })
+        assert_eq!(buckets.get(2).unwrap()[0].to_string(), "ads$image");
+        assert!(buckets.get(4).is_none());
+        assert_eq!(buckets.decoded_len(), 1);
+
+        let tokens: Vec<Hash> = buckets.iter().map(|(token, _)| token).collect();
+        assert_eq!(tokens, vec![1, 2, 3]);
     }
-}
 
+    #[test]
+    fn lazy_buckets_reject_corrupt_data() {
+        let mut filter_map = HashMap::new();
+        filter_map.insert(1 as Hash, vec![NetworkFilter::parse("||foo.com^", true).unwrap().into()]);
+        let mut body = Vec::new();
+        let index = write_buckets(&mut body, &filter_map).unwrap();
+        let data: Rc<dyn AsRef<[u8]>> = Rc::new(body.clone());
+        assert!(LazyBuckets::new(&data, 0, index).is_ok());
+
+        // The bucket claims more filters than it holds
+        let mut corrupt = body.clone();
+        corrupt[0] += 1;
+        let data: Rc<dyn AsRef<[u8]>> = Rc::new(corrupt);
+        assert!(LazyBuckets::new(&data, 0, index).is_err());
+
+        // Sizes that would overflow when computing bounds
+        let data: Rc<dyn AsRef<[u8]>> = Rc::new(body);
+        let index = BucketIndex { offset: u32::MAX, count: u32::MAX };
+        assert!(LazyBuckets::new(&data, 0, index).is_err());
+    }
+
+    #[test]
+    fn indexed_reports_corrupt_filters() {
+        use crate::engine::Engine;
+        use crate::observer::{BlockerEvent, MemoryObserver};
+
+        let engine = Engine::from_rules(&[String::from("||abcd^")]);
+        let mut serialized = engine.serialize_indexed().unwrap();
+        // The hostname of the filter turns into a number, the bucket stays well-formed
+        let at = serialized.windows(5).position(|bytes| bytes == b"\xa4abcd").unwrap();
+        serialized[at] = 0xce;
+
+        let mut corrupt = Engine::from_rules(&[]);
+        corrupt.deserialize_indexed(serialized).unwrap();
+        let observer = Arc::new(MemoryObserver::default());
+        corrupt.with_observer(observer.clone());
+
+        assert!(!corrupt.check_network_urls("https://abcd/ad.js", "https://example.com", "script").matched);
+        assert!(observer.events().iter().any(|event| matches!(event, BlockerEvent::BucketDecodeFailed { .. })));
+    }
+
+    #[test]
+    fn indexed_rejects_truncated_data() {
+        let mut blocker = empty_blocker();
+        blocker.filter_add(NetworkFilter::parse("||foo.com^", true).unwrap()).unwrap();
+        let serialized = serialize_indexed(&blocker).unwrap();
+        assert!(serialized.starts_with(&INDEXED_MAGIC));
+        assert!(deserialize_indexed(serialized.clone()).is_ok());
+
+        assert!(deserialize_indexed::<Vec<u8>>(serialized[..serialized.len() - 1].into()).is_err());
+        assert!(deserialize_indexed::<Vec<u8>>(serialized[..INDEXED_PREAMBLE_SIZE].into()).is_err());
+        assert!(deserialize_indexed(b"not an engine").is_err());
+    }
+}
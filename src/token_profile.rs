@@ -0,0 +1,78 @@
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::request::Request;
+use crate::utils::Hash;
+
+/**
+ * How many requests of a corpus contain each token. Filters are stored under
+ * one of their tokens, and every request checks the buckets of all of its
+ * tokens, so a token that requests rarely contain makes for a bucket that is
+ * rarely checked. See `Blocker::new_with_token_profile`.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenProfile {
+    #[serde(serialize_with = "crate::serialization::serialize_sorted_map")]
+    frequencies: HashMap<Hash, u32>,
+    requests: u32,
+}
+
+impl TokenProfile {
+    pub fn new() -> TokenProfile {
+        TokenProfile::default()
+    }
+
+    /// Profile of the given request URLs. URLs that cannot be parsed are skipped.
+    pub fn from_urls<S: AsRef<str>, I: IntoIterator<Item = S>>(urls: I) -> TokenProfile {
+        let mut profile = TokenProfile::new();
+        for url in urls {
+            if let Ok(request) = Request::from_url(url.as_ref()) {
+                profile.add_request(&request);
+            }
+        }
+        profile
+    }
+
+    pub fn add_request(&mut self, request: &Request) -> &mut TokenProfile {
+        let mut tokens = Vec::new();
+        request.get_tokens(&mut tokens);
+        tokens.sort_unstable();
+        tokens.dedup();
+        for token in tokens {
+            *self.frequencies.entry(token).or_insert(0) += 1;
+        }
+        self.requests += 1;
+        self
+    }
+
+    /// Number of profiled requests containing `token`.
+    pub fn frequency(&self, token: Hash) -> u32 {
+        self.frequencies.get(&token).cloned().unwrap_or(0)
+    }
+
+    /// Number of profiled requests.
+    pub fn requests(&self) -> u32 {
+        self.requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fast_hash;
+
+    #[test]
+    fn counts_requests_containing_token() {
+        let profile = TokenProfile::from_urls([
+            "https://example.com/ads/ads.js",
+            "https://example.com/script.js",
+            "https://cdn.example.org/ads.png",
+            "not a url",
+        ]);
+        assert_eq!(profile.requests(), 3);
+        assert_eq!(profile.frequency(fast_hash("example")), 3);
+        assert_eq!(profile.frequency(fast_hash("ads")), 2);
+        assert_eq!(profile.frequency(fast_hash("script")), 1);
+        assert_eq!(profile.frequency(fast_hash("banner")), 0);
+    }
+}
@@ -41,6 +41,28 @@ fn load_requests() -> Vec<RequestRuleMatch> {
     reqs
 }
 
+#[test]
+fn check_legacy_dat_upgrades_to_versioned_format() {
+    let mut file = File::open("data/rs-ABPFilterParserData.dat").expect("Opening serialization file failed");
+    let mut serialized = Vec::<u8>::new();
+    file.read_to_end(&mut serialized).expect("Reading from serialization file failed");
+    assert!(!serialized.starts_with(&adblock::serialization::MAGIC));
+
+    let mut engine = Engine::from_rules(&[]);
+    engine.deserialize(&serialized).expect("Deserialization failed");
+
+    let url = "https://www.google-analytics.com/analytics.js";
+    let checked = engine.check_network_urls(url, "https://example.com", "script");
+    assert!(checked.matched, "Expected match for {}", url);
+
+    let reserialized = engine.serialize().expect("Serialization failed");
+    assert!(reserialized.starts_with(&adblock::serialization::MAGIC));
+    let mut upgraded_engine = Engine::from_rules(&[]);
+    upgraded_engine.deserialize(&reserialized).expect("Deserialization of upgraded engine failed");
+    let checked = upgraded_engine.check_network_urls(url, "https://example.com", "script");
+    assert!(checked.matched, "Expected match for {}", url);
+}
+
 #[test]
 fn check_works_same_after_deserialization() {
     println!("Loading requests");
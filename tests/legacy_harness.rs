@@ -616,13 +616,13 @@ mod legacy_misc_tests {
     #[test]
     fn host_anchored_filters_parse_correctly() { // Host anchor is calculated correctly
         let filter = NetworkFilter::parse("||test.com$third-party", false).unwrap();
-        assert_eq!(filter.hostname, Some(String::from("test.com")));
+        assert_eq!(filter.hostname.as_deref(), Some("test.com"));
 
         let filter = NetworkFilter::parse("||test.com/ok$third-party", false).unwrap();
-        assert_eq!(filter.hostname, Some(String::from("test.com")));
+        assert_eq!(filter.hostname.as_deref(), Some("test.com"));
 
         let filter = NetworkFilter::parse("||test.com/ok", false).unwrap();
-        assert_eq!(filter.hostname, Some(String::from("test.com")));
+        assert_eq!(filter.hostname.as_deref(), Some("test.com"));
     }
 
     #[test]
@@ -115,3 +115,43 @@ fn check_engine_matching() {
         }
     }
 }
+
+#[test]
+fn check_optimized_engine_matches_unoptimized() {
+    let requests = load_requests();
+    let rules = adblock::utils::rules_from_lists(&[
+        String::from("data/regression-testing/easylist.txt"),
+        String::from("data/regression-testing/easyprivacy.txt"),
+    ]);
+    let optimized = Engine::from_rules_parametrised(&rules, true, true);
+    let unoptimized = Engine::from_rules_parametrised(&rules, true, false);
+
+    let mut source_urls: Vec<&str> = requests.iter()
+        .map(|req| req.sourceUrl.as_str())
+        .filter(|source_url| *source_url != "http://" && *source_url != "https://")
+        .collect();
+    source_urls.sort();
+    source_urls.dedup();
+    assert!(!source_urls.is_empty());
+
+    let mut requests_checked = 0;
+    for (i, req) in requests.iter().enumerate() {
+        if req.url == "http://" || req.url == "https://" {
+            continue;
+        }
+        // Also try a few other sources, so that filters with domain options are exercised
+        let extra_sources = (0..3).map(|offset| source_urls[(i + offset) % source_urls.len()]);
+        for source_url in std::iter::once(req.sourceUrl.as_str()).chain(extra_sources) {
+            let expected = unoptimized.check_network_urls(&req.url, source_url, &req.r#type);
+            let result = optimized.check_network_urls(&req.url, source_url, &req.r#type);
+            assert_eq!(result.matched, expected.matched, "Expected match = {} for {} at {}, typed {}, unoptimized filter {:?}, optimized filter {:?}",
+                expected.matched, req.url, source_url, req.r#type, expected.filter, result.filter);
+            assert_eq!(result.exception.is_some(), expected.exception.is_some(), "Expected exception = {:?} for {} at {}, typed {}",
+                expected.exception, req.url, source_url, req.r#type);
+            assert_eq!(result.redirect, expected.redirect);
+            requests_checked += 1;
+        }
+    }
+
+    assert!(requests_checked > 0);
+}